@@ -0,0 +1,113 @@
+//! Keymaps for special buffers by filetype or buftype (dirbuf, help, quickfix, terminal).
+//! Set buffer-local from `FileType`, `BufEnter` and `TermOpen` autocommands, so they win over
+//! the maps the buffer's plugin sets, and on setup for the buffers already open.
+
+use crate::{
+    Result, log_error, panic_log,
+    keymap_remapping::{keymaps_by_mode, setup_buf_keymap, NvimKeymap},
+    nvim::api::{
+        self, Buffer,
+        opts::{CreateAugroupOpts, CreateAutocmdOpts, OptionOpts},
+        types::AutocmdCallbackArgs,
+    },
+};
+
+use std::{cell::RefCell, collections::HashSet, fmt, rc::Rc};
+
+/// Owner of these keymaps in the keymap registry
+const OWNER: &str = "buffer";
+
+const AUGROUP: &str = "nvim_config_buffer_keymaps";
+
+#[derive(Clone, Debug)]
+pub enum BufferKind {
+    Filetype(String),
+    /// `terminal`, `help`, `quickfix` etc.
+    Buftype(String),
+}
+
+impl BufferKind {
+    fn matches(&self, buf: &Buffer) -> Result<bool> {
+        let opts = OptionOpts::builder().buffer(buf.clone()).build();
+        Ok(match self {
+            BufferKind::Filetype(filetype) => api::get_option_value::<String>("filetype", &opts)? == *filetype,
+            BufferKind::Buftype(buftype) => api::get_option_value::<String>("buftype", &opts)? == *buftype,
+        })
+    }
+}
+
+impl fmt::Display for BufferKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BufferKind::Filetype(filetype) => write!(f, "filetype {filetype}"),
+            BufferKind::Buftype(buftype) => write!(f, "buftype {buftype}"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BufferKeymap {
+    pub kind: BufferKind,
+    pub keymap: NvimKeymap,
+}
+
+/// Buffer handle and index of the keymap, a keymap is set once per buffer
+type Applied = RefCell<HashSet<(i32, usize)>>;
+
+fn apply(keymaps: &[BufferKeymap], applied: &Applied, buf: &mut Buffer) -> Result<()> {
+    if !buf.is_valid() {
+        return Ok(());
+    }
+
+    for (index, keymap) in keymaps.iter().enumerate() {
+        let key = (buf.handle(), index);
+        if applied.borrow().contains(&key) || !keymap.kind.matches(buf)? {
+            continue;
+        }
+        applied.borrow_mut().insert(key);
+
+        for (mode, keymap) in keymaps_by_mode(&keymap.keymap) {
+            setup_buf_keymap(buf, mode, OWNER, keymap)?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn setup_buffer_keymaps(keymaps: Vec<BufferKeymap>) -> Result<()> {
+    let keymaps = Rc::new(keymaps);
+    let applied = Rc::new(Applied::default());
+
+    for mut buf in api::list_bufs().filter(|buf| buf.is_loaded()) {
+        apply(&keymaps, &applied, &mut buf)?;
+    }
+
+    let augroup = api::create_augroup(
+        AUGROUP,
+        &CreateAugroupOpts::builder().clear(true).build(),
+    )?;
+
+    // The buftype of a terminal is only set on `TermOpen`, after `BufEnter`
+    api::create_autocmd(
+        ["FileType", "BufEnter", "TermOpen"],
+        &CreateAutocmdOpts::builder()
+            .group(augroup)
+            .callback(move |mut args: AutocmdCallbackArgs| -> Result<bool> {
+                panic_log::guard("buffer keymaps", || {
+                    _ = apply(&keymaps, &applied, &mut args.buffer).inspect_err(|e| {
+                        log_error!("Error while setting up buffer keymaps: {e}");
+                    });
+                });
+                Ok(false)
+            })
+            .build(),
+    )?;
+
+    Ok(())
+}
+
+/// Stops setting the keymaps on buffers opened from now on, until they are set up again
+pub fn remove_buffer_keymap_autocmds() {
+    // Missing when the setup failed before creating it
+    _ = api::del_augroup_by_name(AUGROUP);
+}
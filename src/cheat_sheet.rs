@@ -0,0 +1,270 @@
+//! `:KeymapExport [dir]` writes the keymaps as Markdown and HTML tables (`keymaps.md`,
+//! `keymaps.html`) to `dir`, `~/.nvim` by default. Works headless, for generating docs:
+//! `nvim --headless -c "KeymapExport docs" -c q`
+
+use crate::{
+    Result, config, log_error, log_info, nvim_dir, panic_log,
+    keymap::{documented_keymaps, DocumentedKeymap},
+    keymap_remapping::{mode_name, NvimAction},
+    nvim::api::{
+        self,
+        opts::CreateCommandOpts,
+        types::{CommandArgs, CommandNArgs},
+    },
+    which_key::DEFAULT_GROUP,
+};
+
+use std::{collections::BTreeMap, fs, path::PathBuf};
+
+const HEADER: [&str; 5] = ["Key", "Stock key", "Modes", "Action", "Description"];
+
+struct Row {
+    key: String,
+    /// Lhs as written in stock Vim keys, if the layout moved it
+    written: Option<String>,
+    modes: String,
+    action: String,
+    description: String,
+}
+
+/// Rows by group, sorted by key
+fn grouped_rows(keymap: &DocumentedKeymap) -> BTreeMap<&str, Vec<Row>> {
+    let mut groups: BTreeMap<&str, Vec<Row>> = BTreeMap::new();
+
+    for (keys, (written, entry)) in &keymap.keymap {
+        // A Rust function has nothing to show but what it is described as
+        let action = match &entry.action {
+            NvimAction::Function(_) => entry.description(),
+            action => action.to_string(),
+        };
+        let modes: Vec<&str> = entry.target_modes(keys).into_iter().filter_map(mode_name).collect();
+        groups
+            .entry(entry.group.as_deref().unwrap_or(DEFAULT_GROUP))
+            .or_default()
+            .push(Row {
+                key: keys.to_string(),
+                written: (written != keys).then(|| written.to_string()),
+                modes: modes.join(", "),
+                action,
+                description: entry.desc.clone().unwrap_or_default(),
+            });
+    }
+    for rows in groups.values_mut() {
+        rows.sort_by(|a, b| a.key.cmp(&b.key));
+    }
+
+    groups
+}
+
+/// Inline code in a table cell, backticks in `text` need a longer delimiter
+fn markdown_code(text: &str) -> String {
+    let text = text.replace('|', "\\|");
+    match text.contains('`') {
+        true => format!("`` {text} ``"),
+        false => format!("`{text}`"),
+    }
+}
+
+fn render_markdown(keymaps: &[DocumentedKeymap]) -> String {
+    let mut lines = vec!["# Keymaps".to_string()];
+
+    for keymap in keymaps {
+        lines.push(String::new());
+        lines.push(format!("## {}", keymap.title));
+
+        for (group, rows) in grouped_rows(keymap) {
+            lines.push(String::new());
+            lines.push(format!("### {group}"));
+            lines.push(String::new());
+            lines.push(format!("| {} |", HEADER.join(" | ")));
+            lines.push(format!("|{}", " --- |".repeat(HEADER.len())));
+            for row in rows {
+                lines.push(format!(
+                    "| {} | {} | {} | {} | {} |",
+                    markdown_code(&row.key),
+                    row.written.as_deref().map(markdown_code).unwrap_or_default(),
+                    row.modes,
+                    markdown_code(&row.action),
+                    row.description.replace('|', "\\|"),
+                ));
+            }
+        }
+    }
+
+    lines.join("\n") + "\n"
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn render_html(keymaps: &[DocumentedKeymap]) -> String {
+    let code = |text: &str| format!("<code>{}</code>", html_escape(text));
+    let mut lines = vec![
+        "<!DOCTYPE html>".to_string(),
+        "<html>".to_string(),
+        "<head><meta charset=\"utf-8\"><title>Keymaps</title></head>".to_string(),
+        "<body>".to_string(),
+        "<h1>Keymaps</h1>".to_string(),
+    ];
+
+    for keymap in keymaps {
+        lines.push(format!("<h2>{}</h2>", html_escape(&keymap.title)));
+
+        for (group, rows) in grouped_rows(keymap) {
+            lines.push(format!("<h3>{}</h3>", html_escape(group)));
+            lines.push("<table>".to_string());
+            let header: String = HEADER.iter().map(|h| format!("<th>{h}</th>")).collect();
+            lines.push(format!("<tr>{header}</tr>"));
+            for row in rows {
+                lines.push(format!(
+                    "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                    code(&row.key),
+                    row.written.as_deref().map(code).unwrap_or_default(),
+                    row.modes,
+                    code(&row.action),
+                    html_escape(&row.description),
+                ));
+            }
+            lines.push("</table>".to_string());
+        }
+    }
+
+    lines.push("</body>".to_string());
+    lines.push("</html>".to_string());
+    lines.join("\n") + "\n"
+}
+
+fn export(dir: PathBuf) -> Result<()> {
+    let keymaps = documented_keymaps(&config::load_config());
+
+    for (name, content) in [
+        ("keymaps.md", render_markdown(&keymaps)),
+        ("keymaps.html", render_html(&keymaps)),
+    ] {
+        let path = dir.join(name);
+        match fs::create_dir_all(&dir).and_then(|()| fs::write(&path, content)) {
+            Ok(()) => log_info!("Keymaps written to {}", path.display()),
+            Err(e) => log_error!("Failed to write {}: {e}", path.display()),
+        }
+    }
+
+    Ok(())
+}
+
+pub fn setup_export_command() -> Result<()> {
+    api::create_user_command(
+        "KeymapExport",
+        |args: CommandArgs| -> Result<()> {
+            let dir = args.fargs.first().map(PathBuf::from).unwrap_or_else(nvim_dir);
+            panic_log::guard("command KeymapExport", || export(dir)).unwrap_or(Ok(()))
+        },
+        &CreateCommandOpts::builder().nargs(CommandNArgs::ZeroOrOne).build(),
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        key::KeySequence,
+        keymap_remapping::{KeymapEntry, KeymapKind},
+    };
+    use std::rc::Rc;
+
+    fn keymap() -> DocumentedKeymap {
+        let entries = [
+            (
+                "<Space>s",
+                "<Space>s",
+                KeymapEntry::new(NvimAction::Command("w".into())).desc("Save").group("Files"),
+            ),
+            (
+                "K",
+                "J",
+                KeymapEntry::new(NvimAction::Keys("20j".into())).kind(KeymapKind::Motion).group("Movement"),
+            ),
+            (
+                "f",
+                "f",
+                KeymapEntry::new(NvimAction::Function(Rc::new(|_| Ok(()))))
+                    .kind(KeymapKind::Motion)
+                    .desc("Leap")
+                    .group("Movement"),
+            ),
+            (
+                "<Bar>",
+                "<Bar>",
+                KeymapEntry::new(NvimAction::Keys("a|b`c".into())).desc("x | y"),
+            ),
+        ];
+
+        DocumentedKeymap {
+            title: "Global".to_string(),
+            keymap: entries
+                .into_iter()
+                .map(|(keys, written, entry)| (KeySequence::from(keys), (KeySequence::from(written), entry)))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn markdown_groups_and_escapes() {
+        let expected = [
+            "# Keymaps",
+            "",
+            "## Global",
+            "",
+            "### Files",
+            "",
+            "| Key | Stock key | Modes | Action | Description |",
+            "| --- | --- | --- | --- | --- |",
+            "| `<Space>s` |  | n, v | `:w` | Save |",
+            "",
+            "### Movement",
+            "",
+            "| Key | Stock key | Modes | Action | Description |",
+            "| --- | --- | --- | --- | --- |",
+            "| `K` | `J` | n, v, o | `20j` |  |",
+            "| `f` |  | n, v, o | `Leap` | Leap |",
+            "",
+            "### Other",
+            "",
+            "| Key | Stock key | Modes | Action | Description |",
+            "| --- | --- | --- | --- | --- |",
+            "| `<Bar>` |  | n, v | `` a\\|b`c `` | x \\| y |",
+        ];
+        assert_eq!(render_markdown(&[keymap()]), expected.join("\n") + "\n");
+    }
+
+    #[test]
+    fn html_escapes_cells() {
+        let html = render_html(&[keymap()]);
+
+        assert!(html.starts_with("<!DOCTYPE html>\n"));
+        assert!(html.ends_with("</body>\n</html>\n"));
+        assert!(html.contains("<h2>Global</h2>\n<h3>Files</h3>\n<table>\n"));
+        let rows = [
+            "<tr><td><code>&lt;Space&gt;s</code></td><td></td><td>n, v</td><td><code>:w</code></td><td>Save</td></tr>",
+            "<tr><td><code>K</code></td><td><code>J</code></td><td>n, v, o</td><td><code>20j</code></td><td></td></tr>",
+            "<tr><td><code>&lt;Bar&gt;</code></td><td></td><td>n, v</td><td><code>a|b`c</code></td><td>x | y</td></tr>",
+        ];
+        for row in rows {
+            assert!(html.contains(row), "{row} missing");
+        }
+    }
+
+    #[test]
+    fn function_actions_show_their_description() {
+        let keymap = keymap();
+        let rows = grouped_rows(&keymap);
+        let leap = rows["Movement"].iter().find(|row| row.key == "f").unwrap();
+        assert_eq!(leap.action, "Leap");
+        assert_eq!(leap.written, None);
+    }
+}
@@ -0,0 +1,335 @@
+use crate::{
+    keymap_remapping::KeymapKind,
+    layout::LayoutConfig,
+    log_warn,
+    logging::LogConfig,
+    mlua::{self, Lua, Value},
+    nvim_dir,
+};
+
+use serde::{Deserialize, de::DeserializeOwned};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigError {
+    #[error("Failed to read config file: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Failed to parse TOML config: {0}")]
+    Toml(#[from] toml::de::Error),
+
+    #[error("Failed to parse JSON config: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("JSON config must be an object")]
+    JsonNotAnObject,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct PluginConfig {
+    pub name: String,
+    #[serde(default)]
+    pub setup_func: Option<String>,
+    #[serde(default)]
+    pub setup: Option<toml::Value>,
+    /// Plugins set up before this one
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+    /// Lua modules that have to be installed
+    #[serde(default)]
+    pub requires_module: Vec<String>,
+    /// `false` drops a default plugin of the same name
+    #[serde(default = "enabled_default")]
+    pub enabled: bool,
+}
+
+fn enabled_default() -> bool {
+    true
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum OptionValue {
+    Bool(bool),
+    Int(i64),
+    Str(String),
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum ActionTarget {
+    Keys { keys: String },
+    Command { command: String },
+    Lua { lua: String },
+    /// Name of a `<Plug>` map, without the `<Plug>`
+    Plug { plug: String },
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct ActionConfig {
+    #[serde(flatten)]
+    pub target: ActionTarget,
+    #[serde(default)]
+    pub scroll: bool,
+    #[serde(default)]
+    pub kind: KeymapKind,
+    #[serde(default)]
+    pub desc: Option<String>,
+    #[serde(default)]
+    pub group: Option<String>,
+}
+
+/// Mode name (as accepted by `parse_mode`) -> lhs -> action
+pub type KeymapsConfig = BTreeMap<String, BTreeMap<String, ActionConfig>>;
+
+/// Every section is optional, `None` means the built-in defaults are used.
+#[derive(Default, Clone, Debug)]
+pub struct Config {
+    /// Set over the default plugins by name
+    pub plugins: Option<Vec<PluginConfig>>,
+    /// Set over the built-in defaults, like `keymaps`
+    pub options: Option<BTreeMap<String, OptionValue>>,
+    pub keymaps: Option<KeymapsConfig>,
+    pub log: Option<LogConfig>,
+    pub layout: Option<LayoutConfig>,
+    /// Lhs of maps set before the config (by plugins) that the cleared modes keep
+    pub preserved_keymaps: Option<Vec<String>>,
+    /// Cleared modes also blank keys with Shift, Alt and any Ctrl combination
+    pub clear_all_modifiers: Option<bool>,
+}
+
+fn config_paths() -> [PathBuf; 2] {
+    let dir = nvim_dir();
+    [dir.join("config.toml"), dir.join("config.json")]
+}
+
+/// JSON `null` is the same as leaving the value out, in objects and arrays
+fn json_to_toml(value: serde_json::Value) -> Option<toml::Value> {
+    use serde_json::Value as Json;
+
+    Some(match value {
+        Json::Null => return None,
+        Json::Bool(b) => toml::Value::Boolean(b),
+        Json::Number(n) => match n.as_i64() {
+            Some(i) => toml::Value::Integer(i),
+            None => toml::Value::Float(n.as_f64()?),
+        },
+        Json::String(s) => toml::Value::String(s),
+        Json::Array(array) => toml::Value::Array(array.into_iter().filter_map(json_to_toml).collect()),
+        Json::Object(map) => toml::Value::Table(
+            map.into_iter()
+                .filter_map(|(k, v)| Some((k, json_to_toml(v)?)))
+                .collect(),
+        ),
+    })
+}
+
+fn parse_table(content: &str, json: bool) -> Result<toml::Table, ConfigError> {
+    if !json {
+        return Ok(toml::from_str(content)?);
+    }
+
+    match json_to_toml(serde_json::from_str(content)?) {
+        Some(toml::Value::Table(table)) => Ok(table),
+        _ => Err(ConfigError::JsonNotAnObject),
+    }
+}
+
+fn read_table(path: &Path) -> Result<toml::Table, ConfigError> {
+    let content = fs::read_to_string(path)?;
+    parse_table(&content, path.extension().is_some_and(|e| e == "json"))
+}
+
+fn parse_section<T: DeserializeOwned>(table: &toml::Table, name: &str) -> Option<T> {
+    let section = table.get(name)?.clone();
+
+    section
+        .try_into()
+        .inspect_err(|e| {
+            log_warn!("Failed to parse config section [{name}], using defaults: {e}");
+        })
+        .ok()
+}
+
+/// Like `parse_section`, but an invalid option only drops that option
+fn parse_options(table: &toml::Table) -> Option<BTreeMap<String, OptionValue>> {
+    let section: toml::Table = parse_section(table, "options")?;
+
+    let options = section
+        .into_iter()
+        .filter_map(|(name, value)| {
+            value
+                .try_into()
+                .inspect_err(|e| log_warn!("Failed to parse option {name}, skipping it: {e}"))
+                .ok()
+                .map(|value| (name, value))
+        })
+        .collect();
+
+    Some(options)
+}
+
+fn config_from_table(table: &toml::Table) -> Config {
+    Config {
+        plugins: parse_section(table, "plugins"),
+        options: parse_options(table),
+        keymaps: parse_section(table, "keymaps"),
+        log: parse_section(table, "log"),
+        layout: parse_section(table, "layout"),
+        preserved_keymaps: parse_section(table, "preserved_keymaps"),
+        clear_all_modifiers: parse_section(table, "clear_all_modifiers"),
+    }
+}
+
+pub fn load_config() -> Config {
+    let Some(path) = config_paths().into_iter().find(|p| p.exists()) else {
+        return Config::default();
+    };
+
+    match read_table(&path) {
+        Ok(table) => config_from_table(&table),
+        Err(e) => {
+            log_warn!("Failed to load {}, using defaults: {e}", path.display());
+            Config::default()
+        }
+    }
+}
+
+/// `defaults` with the entries of `config` in place of the defaults with the same name,
+/// entries with a new name are added at the end
+pub fn overlay<T>(mut defaults: Vec<T>, config: impl IntoIterator<Item = T>, name: impl Fn(&T) -> &str) -> Vec<T> {
+    for entry in config {
+        match defaults.iter().position(|default| name(default) == name(&entry)) {
+            Some(i) => defaults[i] = entry,
+            None => defaults.push(entry),
+        }
+    }
+    defaults
+}
+
+pub fn toml_to_lua<'lua>(lua: &'lua Lua, value: &toml::Value) -> mlua::Result<Value<'lua>> {
+    Ok(match value {
+        toml::Value::String(s) => Value::String(lua.create_string(s)?),
+        toml::Value::Integer(i) => Value::Integer(*i as mlua::Integer),
+        toml::Value::Float(f) => Value::Number(*f),
+        toml::Value::Boolean(b) => Value::Boolean(*b),
+        toml::Value::Datetime(d) => Value::String(lua.create_string(d.to_string())?),
+        toml::Value::Array(array) => {
+            let table = lua.create_table()?;
+            for (i, v) in array.iter().enumerate() {
+                table.raw_set(i + 1, toml_to_lua(lua, v)?)?;
+            }
+            Value::Table(table)
+        }
+        toml::Value::Table(map) => {
+            let table = lua.create_table()?;
+            for (k, v) in map {
+                table.raw_set(k.as_str(), toml_to_lua(lua, v)?)?;
+            }
+            Value::Table(table)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOML: &str = r#"
+        preserved_keymaps = ["<C-j>"]
+
+        [options]
+        tabstop = 2
+        expandtab = false
+        shell = "fish"
+
+        [[plugins]]
+        name = "toggleterm"
+        setup = { direction = "float" }
+
+        [[plugins]]
+        name = "dirbuf"
+        enabled = false
+
+        [keymaps.n]
+        "<Space>g" = { command = "Git", desc = "Git status" }
+    "#;
+
+    const JSON: &str = r#"{
+        "preserved_keymaps": ["<C-j>", null],
+        "options": { "tabstop": 2, "expandtab": false, "shell": "fish", "wrap": null },
+        "plugins": [
+            { "name": "toggleterm", "setup": { "direction": "float", "size": null } },
+            { "name": "dirbuf", "enabled": false, "setup_func": null }
+        ],
+        "keymaps": { "n": { "<Space>g": { "command": "Git", "desc": "Git status", "group": null } } },
+        "layout": null
+    }"#;
+
+    fn options(config: &Config) -> Vec<(&str, String)> {
+        let options = config.options.as_ref().unwrap();
+        options.iter().map(|(name, value)| (name.as_str(), format!("{value:?}"))).collect()
+    }
+
+    #[test]
+    fn json_and_toml_agree() {
+        let toml = config_from_table(&parse_table(TOML, false).unwrap());
+        let json = config_from_table(&parse_table(JSON, true).unwrap());
+
+        assert_eq!(options(&toml), options(&json));
+        assert_eq!(toml.preserved_keymaps, json.preserved_keymaps);
+        assert_eq!(format!("{:?}", toml.plugins), format!("{:?}", json.plugins));
+        assert_eq!(format!("{:?}", toml.keymaps), format!("{:?}", json.keymaps));
+        assert!(toml.layout.is_none() && json.layout.is_none());
+    }
+
+    #[test]
+    fn parses_sections() {
+        let config = config_from_table(&parse_table(TOML, false).unwrap());
+
+        let expected = [("expandtab", "Bool(false)"), ("shell", "Str(\"fish\")"), ("tabstop", "Int(2)")];
+        assert_eq!(options(&config), expected.map(|(name, value)| (name, value.to_string())));
+
+        let plugins = config.plugins.unwrap();
+        assert_eq!(plugins.len(), 2);
+        assert!(plugins[0].enabled && !plugins[1].enabled);
+        assert_eq!(plugins[0].setup.as_ref().and_then(|s| s.get("direction")?.as_str()), Some("float"));
+
+        let action = &config.keymaps.unwrap()["n"]["<Space>g"];
+        assert!(matches!(&action.target, ActionTarget::Command { command } if command == "Git"));
+        assert_eq!(action.kind, KeymapKind::Action);
+
+        assert!(config.log.is_none() && config.clear_all_modifiers.is_none());
+    }
+
+    #[test]
+    fn json_null_is_left_out() {
+        let table = parse_table(r#"{ "a": null, "b": [1, null, 2], "c": { "d": null } }"#, true).unwrap();
+
+        assert!(!table.contains_key("a"));
+        assert_eq!(table["b"], toml::Value::Array(vec![1.into(), 2.into()]));
+        assert_eq!(table["c"], toml::Value::Table(toml::Table::new()));
+    }
+
+    #[test]
+    fn json_must_be_an_object() {
+        assert!(matches!(parse_table("[1, 2]", true), Err(ConfigError::JsonNotAnObject)));
+        assert!(matches!(parse_table("null", true), Err(ConfigError::JsonNotAnObject)));
+        assert!(matches!(parse_table("{", true), Err(ConfigError::Json(_))));
+        assert!(matches!(parse_table("a = ", false), Err(ConfigError::Toml(_))));
+    }
+
+    #[test]
+    fn overlay_replaces_by_name_and_appends() {
+        let defaults = vec![("number", 1), ("tabstop", 4), ("scrolloff", 10)];
+        let configured = [("tabstop", 2), ("wrap", 0)];
+
+        let merged = overlay(defaults, configured, |(name, _)| name);
+
+        assert_eq!(merged, [("number", 1), ("tabstop", 2), ("scrolloff", 10), ("wrap", 0)]);
+    }
+}
@@ -0,0 +1,493 @@
+//! Vim key notation parsed into keys that compare equal however they are spelled:
+//! `<ESC>`/`<Esc>`, `" "`/`<Space>`, `<C-A>`/`<C-a>`, `<S-a>`/`A` and `<M-x>`/`<A-x>` are the same key.
+//!
+//! Displaying a key gives its canonical notation, parsing that again gives back the same key.
+
+use std::{collections::BTreeSet, fmt};
+
+/// Keys that have a name in `<...>` notation, canonical spelling first, then the aliases
+const NAMED_KEYS: &[(&str, &[&str])] = &[
+    ("Esc", &[]),
+    ("CR", &["Return", "Enter"]),
+    ("NL", &["NewLine", "LineFeed", "LF"]),
+    ("Tab", &[]),
+    ("BS", &["Backspace"]),
+    ("Del", &["Delete"]),
+    ("Insert", &["Ins"]),
+    ("Up", &[]),
+    ("Down", &[]),
+    ("Left", &[]),
+    ("Right", &[]),
+    ("Home", &[]),
+    ("End", &[]),
+    ("PageUp", &[]),
+    ("PageDown", &[]),
+    ("Help", &[]),
+    ("Undo", &[]),
+    ("kHome", &[]),
+    ("kEnd", &[]),
+    ("kPageUp", &[]),
+    ("kPageDown", &[]),
+    ("kPlus", &[]),
+    ("kMinus", &[]),
+    ("kMultiply", &[]),
+    ("kDivide", &[]),
+    ("kEnter", &[]),
+    ("kPoint", &[]),
+    ("kComma", &[]),
+    ("kEqual", &[]),
+    ("k0", &[]),
+    ("k1", &[]),
+    ("k2", &[]),
+    ("k3", &[]),
+    ("k4", &[]),
+    ("k5", &[]),
+    ("k6", &[]),
+    ("k7", &[]),
+    ("k8", &[]),
+    ("k9", &[]),
+];
+
+/// Mouse events, only the clicks are part of `all_keys`
+const MOUSE_KEYS: &[&str] = &[
+    "LeftMouse", "MiddleMouse", "RightMouse",
+    "LeftDrag", "MiddleDrag", "RightDrag",
+    "LeftRelease", "MiddleRelease", "RightRelease",
+    "X1Mouse", "X1Drag", "X1Release", "X2Mouse", "X2Drag", "X2Release",
+    "ScrollWheelUp", "ScrollWheelDown", "ScrollWheelLeft", "ScrollWheelRight",
+    "MouseMove",
+];
+const MOUSE_CLICKS: usize = 3;
+
+/// Not typed keys, but valid in a lhs
+const PSEUDO_KEYS: &[&str] = &["Nop", "Plug", "Leader", "LocalLeader", "SID", "Cmd", "Ignore"];
+
+/// Characters that are written as `<name>` in a lhs
+const NAMED_CHARS: &[(char, &str)] = &[(' ', "Space"), ('<', "lt"), ('|', "Bar"), ('\\', "Bslash")];
+
+const MAX_FUNCTION_KEY: u8 = 37;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Modifiers {
+    pub ctrl: bool,
+    pub shift: bool,
+    /// `A-` and `M-` (meta) are the same modifier in nvim
+    pub alt: bool,
+    /// `D-`, the Cmd/Super key, only sent by GUIs
+    pub cmd: bool,
+}
+
+impl Modifiers {
+    pub fn is_empty(&self) -> bool {
+        *self == Modifiers::default()
+    }
+}
+
+impl fmt::Display for Modifiers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (set, prefix) in [(self.ctrl, "C-"), (self.shift, "S-"), (self.alt, "A-"), (self.cmd, "D-")] {
+            if set {
+                f.write_str(prefix)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum KeyCode {
+    Char(char),
+    /// Canonical name from `NAMED_KEYS`, `MOUSE_KEYS` or `PSEUDO_KEYS`
+    Named(&'static str),
+    /// `<F1>` to `<F37>`
+    Function(u8),
+}
+
+impl KeyCode {
+    /// Key for the text of `<name>`, case insensitive like in nvim
+    fn from_name(name: &str) -> Option<KeyCode> {
+        let matches = |candidate: &str| candidate.eq_ignore_ascii_case(name);
+
+        if let Some((c, _)) = NAMED_CHARS.iter().find(|(_, n)| matches(n)) {
+            return Some(KeyCode::Char(*c));
+        }
+        let named = NAMED_KEYS
+            .iter()
+            .find(|(canonical, aliases)| matches(canonical) || aliases.iter().any(|a| matches(a)))
+            .map(|(canonical, _)| *canonical)
+            .or_else(|| MOUSE_KEYS.iter().chain(PSEUDO_KEYS).copied().find(|n| matches(n)));
+        if let Some(canonical) = named {
+            return Some(KeyCode::Named(canonical));
+        }
+
+        name.strip_prefix(['F', 'f'])
+            .filter(|n| !n.starts_with('0'))
+            .and_then(|n| n.parse().ok())
+            .filter(|n| (1..=MAX_FUNCTION_KEY).contains(n))
+            .map(KeyCode::Function)
+    }
+}
+
+impl fmt::Display for KeyCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyCode::Char(c) => match NAMED_CHARS.iter().find(|(named, _)| named == c) {
+                Some((_, name)) => f.write_str(name),
+                None => write!(f, "{c}"),
+            },
+            KeyCode::Named(name) => f.write_str(name),
+            KeyCode::Function(n) => write!(f, "F{n}"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Key {
+    modifiers: Modifiers,
+    code: KeyCode,
+}
+
+impl Key {
+    /// Canonicalizes letters: Shift alone gives the uppercase letter,
+    /// with Ctrl the letter is lowercase (`<C-A>` is `<C-a>`, `<C-S-a>` needs the explicit Shift)
+    pub fn new(mut modifiers: Modifiers, mut code: KeyCode) -> Key {
+        if let KeyCode::Char(c) = code
+            && c.is_ascii_alphabetic()
+        {
+            if modifiers.shift && !modifiers.ctrl {
+                modifiers.shift = false;
+                code = KeyCode::Char(c.to_ascii_uppercase());
+            } else if modifiers.ctrl {
+                code = KeyCode::Char(c.to_ascii_lowercase());
+            }
+        }
+
+        Key { modifiers, code }
+    }
+
+    pub fn char(c: char) -> Key {
+        Key::new(Modifiers::default(), KeyCode::Char(c))
+    }
+
+    /// The character typed by this key, if it inserts one
+    pub fn as_char(&self) -> Option<char> {
+        match self.code {
+            KeyCode::Char(c) if self.modifiers.is_empty() => Some(c),
+            _ => None,
+        }
+    }
+
+    /// Parses the text between `<` and `>`, `None` if it isn't key notation
+    fn from_notation(inner: &str) -> Option<Key> {
+        let mut modifiers = Modifiers::default();
+        let mut rest = inner;
+
+        while let Some((modifier, key)) = rest.split_once('-').filter(|(_, key)| !key.is_empty()) {
+            match modifier.to_ascii_uppercase().as_str() {
+                "C" => modifiers.ctrl = true,
+                "S" => modifiers.shift = true,
+                "A" | "M" => modifiers.alt = true,
+                "D" => modifiers.cmd = true,
+                _ => return None,
+            }
+            rest = key;
+        }
+
+        let mut chars = rest.chars();
+        let code = match (chars.next(), chars.next()) {
+            (Some(c), None) if !modifiers.is_empty() => KeyCode::Char(c),
+            _ => KeyCode::from_name(rest)?,
+        };
+
+        Some(Key::new(modifiers, code))
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.as_char() {
+            Some(c) if !NAMED_CHARS.iter().any(|(named, _)| *named == c) => write!(f, "{c}"),
+            _ => write!(f, "<{}{}>", self.modifiers, self.code),
+        }
+    }
+}
+
+/// A lhs, the `NvimKeymap` key
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct KeySequence(Vec<Key>);
+
+impl KeySequence {
+    pub fn keys(&self) -> &[Key] {
+        &self.0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn starts_with(&self, prefix: &KeySequence) -> bool {
+        self.0.starts_with(&prefix.0)
+    }
+
+    pub fn push(&mut self, key: Key) {
+        self.0.push(key);
+    }
+
+    /// The keys after the first `len`
+    pub fn skip(&self, len: usize) -> KeySequence {
+        KeySequence(self.0.iter().skip(len).copied().collect())
+    }
+}
+
+impl From<&str> for KeySequence {
+    /// Text that isn't key notation (`<foo>`, a lone `<`) is taken literally, as nvim does
+    fn from(lhs: &str) -> Self {
+        let mut keys = Vec::new();
+        let mut rest = lhs;
+
+        while let Some(c) = rest.chars().next() {
+            // `<C->>` ends at the second `>`, so every `>` is tried as the end of the notation
+            let notation = rest.strip_prefix('<').and_then(|inner| {
+                inner
+                    .match_indices('>')
+                    .find_map(|(end, _)| Some((Key::from_notation(&inner[..end])?, end + 2)))
+            });
+
+            match notation {
+                Some((key, len)) => {
+                    keys.push(key);
+                    rest = &rest[len..];
+                }
+                None => {
+                    keys.push(Key::char(c));
+                    rest = &rest[c.len_utf8()..];
+                }
+            }
+        }
+
+        KeySequence(keys)
+    }
+}
+
+impl From<String> for KeySequence {
+    fn from(lhs: String) -> Self {
+        KeySequence::from(lhs.as_str())
+    }
+}
+
+impl From<Key> for KeySequence {
+    fn from(key: Key) -> Self {
+        KeySequence(vec![key])
+    }
+}
+
+impl FromIterator<Key> for KeySequence {
+    fn from_iter<I: IntoIterator<Item = Key>>(keys: I) -> Self {
+        KeySequence(keys.into_iter().collect())
+    }
+}
+
+impl fmt::Display for KeySequence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|key| write!(f, "{key}"))
+    }
+}
+
+/// Named keys blanked alone by `all_keys`, the others keep their default
+const CLEARED_NAMED_KEYS: &[&str] = &[
+    "Esc", "CR", "Tab", "BS", "Del", "Up", "Down", "Left", "Right", "Home", "End", "PageUp", "PageDown",
+    "kHome", "kPlus", "kMinus", "kMultiply", "kDivide", "kEnter", "kPoint",
+    "k0", "k1", "k2", "k3", "k4", "k5", "k6", "k7", "k8", "k9",
+];
+
+/// Named keys blanked with Ctrl by `all_keys`
+const CLEARED_CTRL_NAMED_KEYS: &[&str] = &["Up", "Down", "Left", "Right"];
+
+/// Function keys blanked by `all_keys`, the higher ones are only sent as shifted F-keys
+const CLEARED_FUNCTION_KEYS: u8 = 12;
+
+/// Every key a mapping can start with that nvim has a default for: printable ASCII, the common
+/// named keys, F1-F12 and mouse clicks, and Ctrl with letters, digits, `[`, `]`, Space and arrows.
+///
+/// With `all_modifiers`, every named and function key and each of them with any combination of
+/// Ctrl, Shift and Alt, about a thousand keys. That blanks defaults like `<C-LeftMouse>` too.
+///
+/// `<D-...>` is left out, GUIs like neovide map Cmd themselves (`<D-v>` to paste).
+/// Mouse drags, releases and the wheel are left out so selecting and scrolling keep working.
+pub fn all_keys(all_modifiers: bool) -> Vec<Key> {
+    if all_modifiers {
+        return all_modified_keys();
+    }
+
+    let ctrl = Modifiers { ctrl: true, ..Modifiers::default() };
+    let plain = (' '..='~')
+        .map(KeyCode::Char)
+        .chain(CLEARED_NAMED_KEYS.iter().copied().map(KeyCode::Named))
+        .chain(MOUSE_KEYS[..MOUSE_CLICKS].iter().copied().map(KeyCode::Named))
+        .chain((1..=CLEARED_FUNCTION_KEYS).map(KeyCode::Function))
+        .map(|code| Key::new(Modifiers::default(), code));
+    let with_ctrl = ('a'..='z')
+        .chain('0'..='9')
+        .chain(['[', ']', ' '])
+        .map(KeyCode::Char)
+        .chain(CLEARED_CTRL_NAMED_KEYS.iter().copied().map(KeyCode::Named))
+        .map(|code| Key::new(ctrl, code));
+
+    plain.chain(with_ctrl).collect::<BTreeSet<_>>().into_iter().collect()
+}
+
+fn all_modified_keys() -> Vec<Key> {
+    let codes = (' '..='~')
+        .map(KeyCode::Char)
+        .chain(NAMED_KEYS.iter().map(|&(name, _)| KeyCode::Named(name)))
+        .chain(MOUSE_KEYS[..MOUSE_CLICKS].iter().copied().map(KeyCode::Named))
+        .chain((1..=MAX_FUNCTION_KEY).map(KeyCode::Function));
+
+    let mut keys = BTreeSet::new();
+    for code in codes {
+        for bits in 0..8u8 {
+            let modifiers = Modifiers {
+                ctrl: bits & 1 != 0,
+                shift: bits & 2 != 0,
+                alt: bits & 4 != 0,
+                cmd: false,
+            };
+            // Shifted punctuation and digits arrive as a different character
+            if modifiers.shift && matches!(code, KeyCode::Char(c) if !c.is_ascii_alphabetic()) {
+                continue;
+            }
+            keys.insert(Key::new(modifiers, code));
+        }
+    }
+
+    keys.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Canonical notation of `lhs`
+    fn canonical(lhs: &str) -> String {
+        KeySequence::from(lhs).to_string()
+    }
+
+    fn assert_round_trip(spellings: &[&str], expected: &str) {
+        for lhs in spellings {
+            assert_eq!(canonical(lhs), expected, "canonical notation of {lhs}");
+            assert_eq!(KeySequence::from(expected), KeySequence::from(*lhs), "{expected} parsed again");
+        }
+    }
+
+    #[test]
+    fn named_keys_ignore_case() {
+        assert_round_trip(&["<ESC>", "<Esc>", "<esc>"], "<Esc>");
+        assert_round_trip(&["<Return>", "<CR>", "<Enter>"], "<CR>");
+    }
+
+    #[test]
+    fn named_chars() {
+        assert_round_trip(&[" ", "<Space>"], "<Space>");
+        assert_round_trip(&["<lt>", "<"], "<lt>");
+        assert_round_trip(&["<Bar>", "|"], "<Bar>");
+        assert_round_trip(&["<Bslash>", "\\"], "<Bslash>");
+    }
+
+    #[test]
+    fn letters_with_modifiers() {
+        assert_round_trip(&["<C-A>", "<C-a>", "<c-a>"], "<C-a>");
+        assert_round_trip(&["<S-a>", "A", "<S-A>"], "A");
+        assert_round_trip(&["<C-S-a>", "<C-S-A>"], "<C-S-a>");
+    }
+
+    #[test]
+    fn meta_is_alt() {
+        assert_round_trip(&["<M-x>", "<A-x>", "<m-x>"], "<A-x>");
+    }
+
+    #[test]
+    fn modified_angle_bracket() {
+        assert_round_trip(&["<C->>"], "<C->>");
+        assert_eq!(KeySequence::from("<C->>").len(), 1);
+    }
+
+    #[test]
+    fn sequences() {
+        assert_round_trip(&["<C-W>h<ESC>", "<C-w>h<Esc>"], "<C-w>h<Esc>");
+        assert_round_trip(&["<Space>ff"], "<Space>ff");
+    }
+
+    #[test]
+    fn invalid_notation_is_literal() {
+        assert_eq!(KeySequence::from("<foo>").len(), 5);
+        assert_eq!(canonical("<foo>"), "<lt>foo>");
+    }
+
+    #[test]
+    fn all_keys_round_trip() {
+        for key in all_keys(true) {
+            let lhs = KeySequence::from(key).to_string();
+            assert_eq!(KeySequence::from(lhs.as_str()), KeySequence::from(key), "{lhs}");
+        }
+    }
+
+    #[test]
+    fn all_keys_are_distinct() {
+        for all_modifiers in [false, true] {
+            let keys = all_keys(all_modifiers);
+            let distinct: BTreeSet<_> = keys.iter().map(|key| key.to_string()).collect();
+            assert_eq!(distinct.len(), keys.len());
+        }
+    }
+
+    fn contains(keys: &[Key], lhs: &str) -> bool {
+        keys.contains(&KeySequence::from(lhs).keys()[0])
+    }
+
+    #[test]
+    fn all_keys_default_coverage() {
+        let plain = 95 + CLEARED_NAMED_KEYS.len() + MOUSE_CLICKS + usize::from(CLEARED_FUNCTION_KEYS);
+        let ctrl = 26 + 10 + 3 + CLEARED_CTRL_NAMED_KEYS.len();
+        let keys = all_keys(false);
+        assert_eq!(keys.len(), plain + ctrl);
+        let ctrl_only = Modifiers { ctrl: true, ..Modifiers::default() };
+        assert!(keys.iter().all(|key| key.modifiers.is_empty() || key.modifiers == ctrl_only));
+
+        let expected = [
+            "a", "A", "~", "<Bar>", "<Bslash>", "<Space>", "<Esc>", "<kEnter>", "<k9>", "<F12>",
+            "<LeftMouse>", "<C-a>", "<C-0>", "<C-[>", "<C-]>", "<C-Space>", "<C-Left>",
+        ];
+        for lhs in expected {
+            assert!(contains(&keys, lhs), "{lhs} missing");
+        }
+        for lhs in ["<C-LeftMouse>", "<S-LeftMouse>", "<S-Tab>", "<A-a>", "<C-S-a>", "<F13>", "<Insert>", "<C-;>"] {
+            assert!(!contains(&keys, lhs), "{lhs} cleared");
+        }
+    }
+
+    #[test]
+    fn all_keys_full_coverage() {
+        let letters = 26 * 8;
+        // Shift doesn't combine with the other printable characters
+        let others = (95 - 52) * 4;
+        let named = (NAMED_KEYS.len() + MOUSE_CLICKS + usize::from(MAX_FUNCTION_KEY)) * 8;
+        let keys = all_keys(true);
+        assert_eq!(keys.len(), letters + others + named);
+        assert!(all_keys(false).iter().all(|key| keys.contains(key)));
+
+        let expected = [
+            "a", "A", "<C-a>", "<C-S-a>", "<A-A>", "<C-A-S-a>", "1", "<C-1>",
+            "<A-Space>", "<S-Tab>", "<C-S-A-F37>", "<S-LeftMouse>",
+        ];
+        for lhs in expected {
+            assert!(contains(&keys, lhs), "{lhs} missing");
+        }
+        assert!(!keys.iter().any(|key| key.modifiers.cmd));
+        let shifted_digit = Key::new(Modifiers { shift: true, ..Modifiers::default() }, KeyCode::Char('1'));
+        assert!(!keys.contains(&shifted_digit));
+    }
+}
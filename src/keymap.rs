@@ -1,97 +1,357 @@
 use crate::{
-    keymap_remapping::{setup_keymap, setup_keymap_clean, NvimKeymap},
-    nvim::{self, api::types::Mode},
+    buffer_keymap::{setup_buffer_keymaps, BufferKeymap, BufferKind},
+    config::{ActionConfig, ActionTarget, Config},
+    key::KeySequence,
+    keymap_lint::{self, Issue},
+    keymap_remapping::{
+        keymaps_by_mode, parse_mode, setup_keymap, setup_keymap_clean,
+        KeymapEntry, NvimAction, NvimKeymap,
+    },
+    layout::{LAYOUT_NAMES, Layout},
+    log_warn,
+    nvim::api::types::Mode,
     nvim_keymap,
     plugins::{
         leap::leap,
+        lsp::lsp_keymaps,
         spectre::{spectre_open_file_search, spectre_toggle},
     },
+    report::{self, Subsystem},
 };
 
+use std::collections::{BTreeSet, HashMap};
+
 fn motion_keymap() -> NvimKeymap {
     nvim_keymap![
-        // Movement
-        ("j" => ["h"]), ("k" => ["j"]), ("l" => ["k"]), (";" => ["l"]),
-        ("K" => @ ["20j"]), ("L" => @ ["20k"]),
-        ("!" => @ ["^"]), (@ "$"),
-        (@ "w"), (@ "b"), (@ "e"),
-        ("f" => @ ! leap()),
-        (@ "gg"), (@ "G"),
-        ("<" => ["<C-o>"]), (">" => ["<C-i>"]),
-
-        // Window focus
-        (" j" => ["<C-w>h"]),
-        (" k" => ["<C-w>j"]),
-        (" l" => ["<C-w>k"]),
-        (" ;" => ["<C-w>l"]),
-
-        // Window management
-        (" x" => ["<C-w>c"]),
-        (" c" => ["<C-w>s"]),
-        (" v" => ["<C-w>v"]),
-
-        // Mode-change
-        ("a"), ("i"), ("A"), ("I"),
-        ("o"), ("O"),
-        ("v"), ("V"), ("<C-v>"),
-        (":"),
-        ("<ESC>" => "noh"),
-
-        // Editing
-        ("r"), ("s"), ("x"),
-        ("S"),
-        ("d"), ("y"),
-        ("D" => ["dd"]),
-        ("Y" => ["yy"]),
-        (@ "p"), (@ "P"),
-        ("\""),
-
-        // Other
-        ("<CR>"),
-        ("ze" => "Dirbuf ."),
-        ("E" => "Dirbuf"),
-        ("<C-j>" => "ToggleTerm"),
-
-        // Save etc.
-        (" s" => "w"),
-        (" a" => "q"),
-        (" A" => "q!"),
-        (" e" => "e"),
-        (" E" => "e!"),
-
-        // Undo
-        ("u"), ("U" => ["<C-r>"]),
-        (" u" => "UndotreeToggle"),
-
-        // Search
-        ("/"),
-        ("hh" => "TelescopeCall buffers"),
-        ("zf" => "TelescopeCall find_files"),
-        ("zd" => "TelescopeCall live_grep"),
-        ("?" => "TelescopeCall current_buffer_fuzzy_find"),
-
-        ("RR" => ! spectre_toggle()),
-        ("Rf" => ! spectre_open_file_search()),
+        // h/j/k/l come from the layout, the big steps from `positional_keymap`
+        { "Movement":
+            (motion "!" => @ ["^"]), (motion @ "$"),
+            (motion @ "w"), (motion @ "b"), (motion @ "e"),
+            (motion desc "Leap" "f" => @ ! leap()),
+            (motion @ "gg"), (motion @ "G"),
+            ("<" => ["<C-o>"]), (">" => ["<C-i>"]),
+        },
+
+        { "Window management":
+            (desc "Close window" " x" => ["<C-w>c"]),
+            (desc "Split horizontally" " c" => ["<C-w>s"]),
+            (desc "Split vertically" " v" => ["<C-w>v"]),
+        },
+
+        { "Mode-change":
+            ("a"), ("i"), ("A"), ("I"),
+            ("o"), ("O"),
+            ("v"), ("V"), ("<C-v>"),
+            (":"),
+            (desc "Clear search highlight" "<ESC>" => "noh"),
+        },
+
+        { "Editing":
+            ("r"), ("s"), ("x"),
+            ("S"),
+            (operator "d"), (operator "y"),
+            ("D" => ["dd"]),
+            ("Y" => ["yy"]),
+            (@ "p"), (@ "P"),
+            ("\""),
+            // `.` is the LSP prefix
+            (desc "Repeat last change" "," => ["."]),
+        },
+
+        { "Other":
+            ("<CR>"),
+            (desc "File browser here" "ze" => "Dirbuf ."),
+            (desc "File browser" "E" => "Dirbuf"),
+            (desc "Toggle terminal" "<C-j>" => "ToggleTerm"),
+        },
+
+        { "Files":
+            (desc "Save" " s" => "w"),
+            (desc "Quit" " a" => "q"),
+            (desc "Quit without saving" " A" => "q!"),
+            (desc "Reload file" " e" => "e"),
+            (desc "Discard changes" " E" => "e!"),
+        },
+
+        { "Undo":
+            ("u"), ("U" => ["<C-r>"]),
+            (desc "Undo tree" " u" => "UndotreeToggle"),
+        },
+
+        { "Search":
+            ("/"),
+            (desc "Buffers" "hh" => "TelescopeCall buffers"),
+            (desc "Find files" "zf" => "TelescopeCall find_files"),
+            (desc "Live grep" "zd" => "TelescopeCall live_grep"),
+            (desc "Search in buffer" "?" => "TelescopeCall current_buffer_fuzzy_find"),
+
+            (desc "Search and replace" "RR" => ! spectre_toggle()),
+            (desc "Search and replace in file" "Rf" => ! spectre_open_file_search()),
+        },
+    ]
+}
+
+/// Written in stock Vim keys, moved to the physical keys by the layout
+fn positional_keymap() -> NvimKeymap {
+    nvim_keymap![
+        { "Movement":
+            (motion "J" => @ ["20j"]), (motion "K" => @ ["20k"]),
+        },
+
+        { "Window focus":
+            (desc "Focus left" " h" => ["<C-w>h"]),
+            (desc "Focus down" " j" => ["<C-w>j"]),
+            (desc "Focus up" " k" => ["<C-w>k"]),
+            (desc "Focus right" " l" => ["<C-w>l"]),
+        },
+
+        { "Window" sticky " w":
+            (desc "Focus left" "h" => ["<C-w>h"]),
+            (desc "Focus down" "j" => ["<C-w>j"]),
+            (desc "Focus up" "k" => ["<C-w>k"]),
+            (desc "Focus right" "l" => ["<C-w>l"]),
+            (desc "Close" "x" => ["<C-w>c"]),
+            (desc "Split horizontally" "c" => ["<C-w>s"]),
+            (desc "Split vertically" "v" => ["<C-w>v"]),
+            (desc "Swap with next" "s" => ["<C-w>x"]),
+            (desc "Taller" "+" => ["<C-w>+"]),
+            (desc "Shorter" "-" => ["<C-w>-"]),
+            (desc "Wider" ">" => ["<C-w>>"]),
+            (desc "Narrower" "<lt>" => ["<C-w><lt>"]),
+            (desc "Equal size" "=" => ["<C-w>="]),
+        },
     ]
 }
 
+/// Separate from the global keymap, the lhs of an entry is the same in every mode
 fn terminal_keymap() -> NvimKeymap {
     nvim_keymap! {
-        ("<ESC>" => ["<C-\\><C-n>"]),
+        ([t] desc "Normal mode" "<ESC>" => ["<C-\\><C-n>"]),
     }
 }
 
-pub fn setup_keymaps() {
-    let motion_modes = [Mode::Normal, Mode::Visual];
+/// Special buffers, the movement keys of the layout come on top, in case the buffer maps them
+fn buffer_keymaps(layout: &Layout) -> Vec<BufferKeymap> {
+    let with_movement = |kind: BufferKind, mut keymap: NvimKeymap| {
+        keymap.merge(layout.keymap());
+        BufferKeymap { kind, keymap }
+    };
+
+    vec![
+        with_movement(BufferKind::Filetype("dirbuf".to_string()), nvim_keymap![
+            { "Files":
+                (desc "Parent directory" "<BS>" => plug "(dirbuf_up)"),
+                (desc "Open" "<CR>" => plug "(dirbuf_enter)"),
+                (desc "Toggle hidden files" "zh" => plug "(dirbuf_toggle_hidden)"),
+                (desc "Close file browser" "q" => "DirbufQuit"),
+            },
+        ]),
+        with_movement(BufferKind::Buftype("help".to_string()), nvim_keymap![
+            (desc "Close help" "q" => "close"),
+            (desc "Follow link" "<CR>" => ["<C-]>"]),
+        ]),
+        with_movement(BufferKind::Buftype("quickfix".to_string()), nvim_keymap![
+            (desc "Close list" "q" => "close"),
+            (desc "Preview entry" "p" => ["<CR><C-w>p"]),
+        ]),
+        with_movement(BufferKind::Buftype("terminal".to_string()), nvim_keymap![
+            (desc "Hide terminal" "q" => "ToggleTerm"),
+        ]),
+    ]
+}
+
+fn config_entry(config: &ActionConfig) -> KeymapEntry {
+    let action = match &config.target {
+        ActionTarget::Keys { keys } => NvimAction::Keys(keys.clone()),
+        ActionTarget::Command { command } => NvimAction::Command(command.clone()),
+        ActionTarget::Lua { lua } => NvimAction::Lua(lua.clone()),
+        ActionTarget::Plug { plug } => NvimAction::Plug(plug.clone()),
+    };
+
+    let mut entry = KeymapEntry::new(action)
+        .kind(config.kind)
+        .scroll(config.scroll);
+    entry.desc = config.desc.clone();
+    entry.group = config.group.clone();
+    entry
+}
+
+/// Entries of the `[keymaps.<name>]` section, mapped in that mode. Motions in normal mode
+/// also work after an operator, like the built-in ones.
+fn config_keymap(config: &Config, name: &str) -> NvimKeymap {
+    let Some(mode) = parse_mode(name) else {
+        return NvimKeymap::new();
+    };
+
+    config.keymaps.as_ref()
+        .and_then(|keymaps| keymaps.get(name))
+        .map(|keymap| {
+            keymap.iter()
+                .map(|(keys, action)| {
+                    let keys = KeySequence::from(keys.as_str());
+                    let entry = config_entry(action);
+                    let modes = match mode {
+                        Mode::Normal => entry.target_modes(&keys)
+                            .into_iter()
+                            .filter(|m| *m != Mode::Visual)
+                            .collect(),
+                        _ => vec![mode],
+                    };
+                    (keys, entry.modes(modes))
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Values are paired with the lhs as written, in stock Vim keys for positional entries
+pub type WrittenKeymap = HashMap<KeySequence, (KeySequence, KeymapEntry)>;
+
+fn written_as_mapped(keymap: NvimKeymap) -> WrittenKeymap {
+    keymap.into_iter().map(|(keys, entry)| (keys.clone(), (keys, entry))).collect()
+}
 
-    let keymap = motion_keymap();
-    for mode in motion_modes {
-        let keymap = keymap.clone();
-        if let Err(e) = setup_keymap_clean(mode, keymap) {
-            nvim::print!("Failed to setup motion keymap for {mode:?}: {e}");
+/// Global keymap, with the layout applied
+fn motion_keymap_with_layout(layout: &Layout) -> WrittenKeymap {
+    let mut keymap = written_as_mapped(motion_keymap());
+    keymap.extend(
+        positional_keymap()
+            .into_iter()
+            .map(|(keys, mut entry)| {
+                if let NvimAction::Submode(submode) = &entry.action {
+                    entry.action = NvimAction::Submode(submode.map_keys(|keys| layout.transform_lhs(keys)));
+                }
+                (layout.transform_lhs(&keys), (keys, entry))
+            }),
+    );
+    keymap.extend(written_as_mapped(layout.keymap()));
+    keymap
+}
+
+/// Problems of the global keymap under each built-in layout, so switching layouts doesn't
+/// bring them in unnoticed: prefix conflicts, and built-in maps a positional one lands on
+pub fn layout_issues() -> Vec<Issue> {
+    let fixed = motion_keymap();
+    let mut issues = Vec::new();
+
+    for name in LAYOUT_NAMES {
+        let Some(layout) = Layout::named(name) else {
+            continue;
         };
+
+        for (written, _) in positional_keymap() {
+            let keys = layout.transform_lhs(&written);
+            if fixed.contains_key(&keys) {
+                issues.push(Issue::Duplicate {
+                    context: format!("{name} layout"),
+                    keys: keys.to_string(),
+                    spellings: vec![keys.to_string(), format!("{written} (positional)")],
+                });
+            }
+        }
+
+        let global: NvimKeymap = motion_keymap_with_layout(&layout)
+            .into_iter()
+            .map(|(keys, (_, entry))| (keys, entry))
+            .collect();
+        for (mode, keymap) in keymaps_by_mode(&global) {
+            let keys: BTreeSet<KeySequence> = keymap.keys().cloned().collect();
+            issues.extend(keymap_lint::prefix_issues(format!("{name} layout, {mode:?}"), &keys, |_| true));
+        }
     }
-    if let Err(e) = setup_keymap(Mode::Terminal, terminal_keymap()) {
-        nvim::print!("Failed to setup terminal keymap: {e}");
-    };
+
+    issues
+}
+
+/// A keymap as listed in the cheat sheet
+pub struct DocumentedKeymap {
+    pub title: String,
+    pub keymap: WrittenKeymap,
+}
+
+/// Everything `setup_keymaps` and the LSP `on_attach` map, for `:KeymapExport`
+pub fn documented_keymaps(config: &Config) -> Vec<DocumentedKeymap> {
+    let layout = Layout::from_config(config.layout.as_ref());
+
+    let mut keymaps = vec![
+        DocumentedKeymap {
+            title: "Global".to_string(),
+            keymap: motion_keymap_with_layout(&layout),
+        },
+        DocumentedKeymap {
+            title: "Terminal mode".to_string(),
+            keymap: written_as_mapped(terminal_keymap()),
+        },
+        DocumentedKeymap {
+            title: "LSP, buffer-local".to_string(),
+            keymap: written_as_mapped(lsp_keymaps()),
+        },
+    ];
+
+    for buffer_keymap in buffer_keymaps(&layout) {
+        keymaps.push(DocumentedKeymap {
+            title: format!("Buffer-local, {}", buffer_keymap.kind),
+            keymap: written_as_mapped(buffer_keymap.keymap),
+        });
+    }
+
+    for name in config.keymaps.iter().flat_map(|keymaps| keymaps.keys()) {
+        keymaps.push(DocumentedKeymap {
+            title: format!("Config file ({name})"),
+            keymap: written_as_mapped(config_keymap(config, name)),
+        });
+    }
+
+    keymaps
+}
+
+/// Built-in keymaps and the config per mode, the config replaces built-in entries of the same lhs
+fn keymaps_per_mode(config: &Config, layout: &Layout) -> Vec<(Mode, NvimKeymap)> {
+    let global: NvimKeymap = motion_keymap_with_layout(layout)
+        .into_iter()
+        .map(|(keys, (_, entry))| (keys, entry))
+        .collect();
+
+    let mut keymaps = vec![global, terminal_keymap()];
+    for name in config.keymaps.iter().flat_map(|keymaps| keymaps.keys()) {
+        match parse_mode(name) {
+            Some(_) => keymaps.push(config_keymap(config, name)),
+            None => log_warn!("Unknown keymap mode in config: {name}"),
+        }
+    }
+
+    let mut per_mode: Vec<(Mode, NvimKeymap)> = Vec::new();
+    for keymap in &keymaps {
+        for (mode, keymap) in keymaps_by_mode(keymap) {
+            match per_mode.iter_mut().find(|(m, _)| *m == mode) {
+                Some((_, existing)) => existing.override_with(keymap),
+                None => per_mode.push((mode, keymap)),
+            }
+        }
+    }
+
+    per_mode
+}
+
+pub fn setup_keymaps(config: &Config) {
+    let layout = Layout::from_config(config.layout.as_ref());
+    let preserved: Vec<KeySequence> = config.preserved_keymaps.iter()
+        .flatten()
+        .map(|lhs| KeySequence::from(lhs.as_str()))
+        .collect();
+
+    let all_modifiers = config.clear_all_modifiers.unwrap_or(false);
+
+    for (mode, keymap) in keymaps_per_mode(config, &layout) {
+        _ = report::timed(Subsystem::Keymap, &format!("{mode:?}"), || match mode {
+            // Other modes aren't cleared, so text objects and other defaults keep working
+            // after an operator, and insert mode keeps its keys
+            Mode::Normal | Mode::Visual => setup_keymap_clean(mode, keymap, &preserved, all_modifiers),
+            _ => setup_keymap(mode, keymap),
+        });
+    }
+
+    _ = report::timed(Subsystem::Keymap, "buffer-local", || {
+        setup_buffer_keymaps(buffer_keymaps(&layout))
+    });
 }
@@ -0,0 +1,283 @@
+//! Checks the keymaps registered through `setup_keymap` / `setup_buf_keymap` for conflicts
+//! nvim doesn't complain about: the same lhs spelled twice, mappings that are a prefix of
+//! another one (nvim waits `timeoutlen` after the prefix) and buffer-local maps hiding global ones.
+//! `:KeymapLint` also checks the global keymap under every built-in layout.
+
+use crate::{
+    Result, log_warn, panic_log,
+    nvim::api::{
+        self, Buffer,
+        opts::CreateCommandOpts,
+        types::{CommandArgs, Mode},
+    },
+    key::KeySequence,
+    keymap,
+    scratch::show_scratch,
+};
+
+use std::{cell::RefCell, collections::BTreeSet, fmt};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Issue {
+    /// The lhs is defined more than once, only one definition is used
+    Duplicate { context: String, keys: String, spellings: Vec<String> },
+    /// Typing `prefix` waits for `timeoutlen` in case one of `keys` follows
+    Prefix { context: String, prefix: String, keys: Vec<String> },
+    /// A buffer-local map hides the global one for the same lhs
+    Shadow { context: String, keys: String },
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Issue::Duplicate { context, keys, spellings } => {
+                write!(f, "duplicate ({context}): {keys} defined as {}", spellings.join(", "))
+            }
+            Issue::Prefix { context, prefix, keys } => {
+                write!(f, "prefix ({context}): {prefix} waits for {}", keys.join(", "))
+            }
+            Issue::Shadow { context, keys } => {
+                write!(f, "shadow ({context}): {keys} hides the global mapping")
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    duplicates: Vec<Issue>,
+    /// Lhs registered per mode and buffer (`None` for global maps)
+    registered: Vec<(Mode, Option<i32>, BTreeSet<KeySequence>)>,
+}
+
+thread_local! {
+    static STATE: RefCell<State> = RefCell::new(State::default());
+}
+
+fn context(mode: Mode, buffer: Option<i32>) -> String {
+    match buffer {
+        Some(handle) => format!("{mode:?}, buffer {handle}"),
+        None => format!("{mode:?}"),
+    }
+}
+
+fn record_duplicate(issue: Issue) {
+    STATE.with_borrow_mut(|s| {
+        if !s.duplicates.contains(&issue) {
+            log_warn!("Keymap {issue}");
+            s.duplicates.push(issue);
+        }
+    });
+}
+
+fn duplicates<'a>(lhs: impl IntoIterator<Item = &'a str>) -> Vec<Issue> {
+    let mut spellings: Vec<(KeySequence, Vec<String>)> = Vec::new();
+    for lhs in lhs {
+        let keys = KeySequence::from(lhs);
+        match spellings.iter_mut().find(|(k, _)| *k == keys) {
+            Some((_, s)) => s.push(lhs.to_string()),
+            None => spellings.push((keys, vec![lhs.to_string()])),
+        }
+    }
+
+    spellings
+        .into_iter()
+        .filter(|(_, spellings)| spellings.len() > 1)
+        .map(|(keys, spellings)| Issue::Duplicate {
+            context: "nvim_keymap!".to_string(),
+            keys: keys.to_string(),
+            spellings,
+        })
+        .collect()
+}
+
+/// Reports lhs given more than once to `nvim_keymap!`, also when spelled differently
+/// (`<ESC>` and `<Esc>`), only one of them would end up in the keymap
+pub fn check_definitions<'a>(lhs: impl IntoIterator<Item = &'a str>) {
+    duplicates(lhs).into_iter().for_each(record_duplicate);
+}
+
+/// Records the lhs set up for `mode`, checked for prefix conflicts and shadowing by `issues`
+pub fn register<'a>(mode: Mode, buffer: Option<i32>, lhs: impl IntoIterator<Item = &'a KeySequence>) {
+    STATE.with_borrow_mut(|s| {
+        let index = match s.registered.iter().position(|(m, b, _)| *m == mode && *b == buffer) {
+            Some(index) => index,
+            None => {
+                s.registered.push((mode, buffer, BTreeSet::new()));
+                s.registered.len() - 1
+            }
+        };
+        s.registered[index].2.extend(lhs.into_iter().cloned());
+    });
+}
+
+/// Forgets the lhs of `mode` and `buffer`, after they were removed
+pub fn unregister(mode: Mode, buffer: Option<i32>) {
+    STATE.with_borrow_mut(|s| s.registered.retain(|(m, b, _)| !(*m == mode && *b == buffer)));
+}
+
+pub fn prefix_issues(context: String, keys: &BTreeSet<KeySequence>, relevant: impl Fn(&KeySequence) -> bool) -> Vec<Issue> {
+    keys.iter()
+        .filter_map(|prefix| {
+            let longer: Vec<String> = keys.iter()
+                .filter(|k| k.len() > prefix.len() && k.starts_with(prefix))
+                .filter(|k| relevant(prefix) || relevant(k))
+                .map(|k| k.to_string())
+                .collect();
+            (!longer.is_empty()).then(|| Issue::Prefix {
+                context: context.clone(),
+                prefix: prefix.to_string(),
+                keys: longer,
+            })
+        })
+        .collect()
+}
+
+/// Global maps of `mode` hidden by the buffer-local `keys`, and prefix conflicts involving them
+fn buffer_issues(context: String, keys: &BTreeSet<KeySequence>, global: &BTreeSet<KeySequence>) -> Vec<Issue> {
+    let mut issues: Vec<Issue> = keys
+        .intersection(global)
+        .map(|shadowed| Issue::Shadow {
+            context: context.clone(),
+            keys: shadowed.to_string(),
+        })
+        .collect();
+
+    // Conflicts between two global maps are reported with the global ones
+    let all: BTreeSet<KeySequence> = keys.union(global).cloned().collect();
+    issues.extend(prefix_issues(context, &all, |k| keys.contains(k)));
+
+    issues
+}
+
+pub fn issues() -> Vec<Issue> {
+    STATE.with_borrow(|s| {
+        let mut issues = s.duplicates.clone();
+
+        let global = |mode: Mode| {
+            s.registered.iter()
+                .find(|(m, b, _)| *m == mode && b.is_none())
+                .map(|(_, _, set)| set.clone())
+                .unwrap_or_default()
+        };
+
+        for (mode, buffer, keys) in &s.registered {
+            let Some(handle) = *buffer else {
+                issues.extend(prefix_issues(context(*mode, None), keys, |_| true));
+                continue;
+            };
+            if !Buffer::from(handle).is_valid() {
+                continue;
+            }
+
+            issues.extend(buffer_issues(context(*mode, *buffer), keys, &global(*mode)));
+        }
+
+        issues
+    })
+}
+
+pub fn clear() {
+    STATE.take();
+}
+
+fn render_lint() -> Vec<String> {
+    let mut issues = issues();
+    issues.extend(keymap::layout_issues());
+
+    let mut lines = vec![format!("{} keymap issue(s)", issues.len()), String::new()];
+    lines.extend(issues.iter().map(Issue::to_string));
+
+    lines
+}
+
+pub fn setup_lint_command() -> Result<()> {
+    api::create_user_command(
+        "KeymapLint",
+        |_: CommandArgs| -> Result<()> {
+            panic_log::guard("command KeymapLint", || {
+                show_scratch("nvim-config://keymap-lint", render_lint())
+            })
+            .unwrap_or(Ok(()))
+        },
+        &CreateCommandOpts::default(),
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(lhs: &[&str]) -> BTreeSet<KeySequence> {
+        lhs.iter().map(|lhs| KeySequence::from(*lhs)).collect()
+    }
+
+    fn prefix(prefix: &str, keys: &[&str]) -> Issue {
+        Issue::Prefix {
+            context: "test".to_string(),
+            prefix: prefix.to_string(),
+            keys: keys.iter().map(|k| k.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn duplicate_spellings() {
+        let issues = duplicates(["<ESC>", "a", "<Esc>", "<C-A>", "<c-a>", " "]);
+        let expected = [
+            Issue::Duplicate {
+                context: "nvim_keymap!".to_string(),
+                keys: "<Esc>".to_string(),
+                spellings: vec!["<ESC>".to_string(), "<Esc>".to_string()],
+            },
+            Issue::Duplicate {
+                context: "nvim_keymap!".to_string(),
+                keys: "<C-a>".to_string(),
+                spellings: vec!["<C-A>".to_string(), "<c-a>".to_string()],
+            },
+        ];
+        assert_eq!(issues, expected);
+    }
+
+    #[test]
+    fn distinct_lhs_are_no_duplicates() {
+        assert!(duplicates(["a", "A", "<S-a>b", "<Space>x", "x"]).is_empty());
+    }
+
+    #[test]
+    fn prefix_of_longer_maps() {
+        let issues = prefix_issues("test".to_string(), &keys(&["h", "hh", "hj", "<Space>", "<Space>w"]), |_| true);
+        assert_eq!(issues, [prefix("<Space>", &["<Space>w"]), prefix("h", &["hh", "hj"])]);
+    }
+
+    #[test]
+    fn exact_match_is_not_a_prefix() {
+        // Spelled differently, but the same keys
+        let issues = prefix_issues("test".to_string(), &keys(&["<Esc>", "<ESC>", "ab", "ba"]), |_| true);
+        assert!(issues.is_empty());
+    }
+
+    #[test]
+    fn prefix_counts_keys_not_characters() {
+        // `<C-w>` starts with `<` as text, not as keys
+        let issues = prefix_issues("test".to_string(), &keys(&["<lt>", "<C-w>", "<C-w>h"]), |_| true);
+        assert_eq!(issues, [prefix("<C-w>", &["<C-w>h"])]);
+    }
+
+    #[test]
+    fn buffer_maps_shadow_and_extend_global_prefixes() {
+        let buffer = keys(&["q", "gq", "zz"]);
+        let global = keys(&["q", "g", "gg", "z"]);
+
+        let issues = buffer_issues("test".to_string(), &buffer, &global);
+
+        // `g` waiting for `gg` is a conflict between global maps, reported with those
+        let expected = [
+            Issue::Shadow { context: "test".to_string(), keys: "q".to_string() },
+            prefix("g", &["gq"]),
+            prefix("z", &["zz"]),
+        ];
+        assert_eq!(issues, expected);
+    }
+}
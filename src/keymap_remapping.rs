@@ -1,193 +1,789 @@
-use std::{collections::HashMap, rc::Rc};
-use std::panic::catch_unwind;
+use std::{cell::RefCell, collections::BTreeMap, fmt, rc::Rc};
 
 use crate::Result;
 
 use crate::nvim::api as api;
-use api::{types::Mode, opts::SetKeymapOpts, Buffer};
-use crate::nvim;
+use api::{types::Mode, opts::{OptionOpts, SetKeymapOpts}, Buffer};
+use crate::mlua::{self, Function, Table};
+use crate::nvim_helper::{lua::lua_get_global_path, lua_value};
+use crate::{keymap_lint, keymap_snapshot, panic_log, profile, reload, repeat, which_key};
+use crate::submode::Submode;
+use crate::keymap_snapshot::{current_maps, SavedMap};
+use crate::key::{all_keys, KeySequence};
+use crate::log_error;
+use crate::plugins::cinnamon::wrap_action;
+
+use serde::Deserialize;
+
+/// What a function keymap was triggered with, read when the keymap runs
+#[derive(Clone)]
+pub struct KeymapContext {
+    /// `v:count`, 0 without a count
+    pub count: u32,
+    /// `v:register`
+    pub register: String,
+    /// `mode(1)`, "no" and its variants in operator-pending mode
+    pub mode: String,
+    pub buffer: Buffer,
+}
+
+impl KeymapContext {
+    pub fn current() -> Result<Self> {
+        let mode: Function = lua_get_global_path("vim.fn.mode")?;
+
+        Ok(KeymapContext {
+            count: lua_get_global_path("vim.v.count")?,
+            register: lua_get_global_path("vim.v.register")?,
+            mode: mode.call(1)?,
+            buffer: api::get_current_buf(),
+        })
+    }
+
+    /// `v:count1`, the count with 1 as the default
+    #[allow(dead_code)]
+    pub fn count1(&self) -> u32 {
+        self.count.max(1)
+    }
+
+    pub fn operator_pending(&self) -> bool {
+        self.mode.starts_with("no")
+    }
+
+    /// Normal, visual or operator-pending mode, where `:` opens the command line
+    fn colon_opens_command_line(&self) -> bool {
+        self.mode.starts_with(['n', 'v', 'V', '\x16'])
+    }
+
+    /// `keys` as if typed after the count. A count at the start of `keys` is multiplied,
+    /// so `5` before `20j` gives `100j` instead of `520j`.
+    pub fn apply_count(&self, keys: &str) -> String {
+        if self.count == 0 || keys.starts_with('0') {
+            return keys.to_string();
+        }
 
-pub type KeymapFunction = Rc<dyn Fn() -> Result<()>>;
+        let digits = keys.len() - keys.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        match keys[..digits].parse::<u32>() {
+            Ok(n) => format!("{}{}", n.saturating_mul(self.count), &keys[digits..]),
+            Err(_) => format!("{}{keys}", self.count),
+        }
+    }
+}
+
+pub type KeymapFunction = Rc<dyn Fn(&KeymapContext) -> Result<()>>;
+
+/// What `NvimAction::Conditional` checks, for the buffer and window the keymap was triggered in
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub enum Condition {
+    Filetype(String),
+    LspAttached,
+    FloatingWindow,
+}
+
+impl Condition {
+    fn check(&self, context: &KeymapContext) -> Result<bool> {
+        Ok(match self {
+            Condition::Filetype(filetype) => {
+                let opts = OptionOpts::builder().buffer(context.buffer.clone()).build();
+                api::get_option_value::<String>("filetype", &opts)? == *filetype
+            }
+            Condition::LspAttached => {
+                let get_clients: Function = lua_get_global_path("vim.lsp.get_clients")?;
+                let clients: Table = get_clients.call(lua_value!({ "bufnr" => context.buffer.handle() }))?;
+                clients.raw_len() > 0
+            }
+            Condition::FloatingWindow => {
+                let get_config: Function = lua_get_global_path("vim.api.nvim_win_get_config")?;
+                let config: Table = get_config.call(0)?;
+                !config.get::<_, String>("relative")?.is_empty()
+            }
+        })
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Condition::Filetype(filetype) => write!(f, "filetype {filetype}"),
+            Condition::LspAttached => f.write_str("LSP attached"),
+            Condition::FloatingWindow => f.write_str("floating window"),
+        }
+    }
+}
 
 #[derive(Clone)]
 pub enum NvimAction {
     Keys(String),
     Command(String),
     Function(KeymapFunction),
+    /// Inline Lua chunk
+    Lua(String),
+    /// Actions run one after the other
+    #[allow(dead_code)]
+    Sequence(Vec<NvimAction>),
+    /// `then` if the condition holds, else `otherwise` or nothing
+    #[allow(dead_code)]
+    Conditional {
+        condition: Condition,
+        then: Box<NvimAction>,
+        otherwise: Option<Box<NvimAction>>,
+    },
+    /// A `<Plug>` map, by the name after `<Plug>`
+    Plug(String),
+    /// Enters the submode, its keys stay active until it is left
+    Submode(Submode),
 }
 
-pub type NvimKeymap = HashMap<String, NvimAction>;
+impl NvimAction {
+    /// Runs the action from a callback, keys and commands are fed as if typed
+    pub fn run(&self, context: &KeymapContext) -> Result<()> {
+        self.execute(context, false)
+    }
 
-// TODO: Create a separate type and implement Debug instead
-#[allow(dead_code)]
-pub fn print_keymap(keymap: &NvimKeymap) {
-    for (keys, action) in keymap {
-        nvim::print!("[{keys}] -> {}\n", match action {
-            NvimAction::Keys(k) => format!("[{k}]"),
-            NvimAction::Command(c) => c.to_owned(),
-            NvimAction::Function(_) => "<lua_function>".to_string(),
-        });
+    /// Runs the action and the keys it feeds before returning, for actions run one after the other
+    pub fn run_immediately(&self, context: &KeymapContext) -> Result<()> {
+        self.execute(context, true)
+    }
+
+    /// `immediate` executes fed keys right away, so that the next action of a sequence sees their effect
+    fn execute(&self, context: &KeymapContext, immediate: bool) -> Result<()> {
+        let flags = |mode: &str| match immediate {
+            true => format!("{mode}x!"),
+            false => mode.to_string(),
+        };
+
+        match self {
+            NvimAction::Keys(keys) => feedkeys(&context.apply_count(keys), &flags("n")),
+            NvimAction::Command(cmd) => feedkeys(&command_keys(cmd, context.colon_opens_command_line()), &flags("n")),
+            NvimAction::Plug(name) => feedkeys(&context.apply_count(&format!("<Plug>{name}")), &flags("m")),
+            NvimAction::Function(func) => func(context),
+            NvimAction::Submode(submode) => submode.run(context),
+            NvimAction::Lua(chunk) => {
+                mlua::lua().load(chunk.as_str()).exec()?;
+                Ok(())
+            }
+            NvimAction::Sequence(actions) => {
+                // The count belongs to the first step, `5<Space>f` shouldn't make a 5 column split
+                let uncounted = KeymapContext { count: 0, ..context.clone() };
+                for (i, action) in actions.iter().enumerate() {
+                    let context = if i == 0 { context } else { &uncounted };
+                    action.execute(context, immediate || i + 1 < actions.len())?;
+                }
+                Ok(())
+            }
+            NvimAction::Conditional { condition, then, otherwise } => {
+                if condition.check(context)? {
+                    then.execute(context, immediate)
+                } else if let Some(otherwise) = otherwise {
+                    otherwise.execute(context, immediate)
+                } else {
+                    Ok(())
+                }
+            }
+        }
     }
 }
 
-const ALL_KEYS: [&str; 185] = [
-    // Lowercase letters
-    "a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k", "l", "m", "n", "o", "p", "q", "r", "s", "t", "u", "v", "w", "x", "y", "z",
+impl fmt::Debug for NvimAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NvimAction::Keys(k) => f.debug_tuple("Keys").field(k).finish(),
+            NvimAction::Command(c) => f.debug_tuple("Command").field(c).finish(),
+            NvimAction::Function(_) => f.write_str("Function(..)"),
+            NvimAction::Lua(chunk) => f.debug_tuple("Lua").field(chunk).finish(),
+            NvimAction::Sequence(actions) => f.debug_tuple("Sequence").field(actions).finish(),
+            NvimAction::Conditional { condition, then, otherwise } => f
+                .debug_struct("Conditional")
+                .field("condition", condition)
+                .field("then", then)
+                .field("otherwise", otherwise)
+                .finish(),
+            NvimAction::Plug(name) => f.debug_tuple("Plug").field(name).finish(),
+            NvimAction::Submode(submode) => f.debug_tuple("Submode").field(submode).finish(),
+        }
+    }
+}
 
-    // Uppercase letters
-    "A", "B", "C", "D", "E", "F", "G", "H", "I", "J", "K", "L", "M", "N", "O", "P", "Q", "R", "S", "T", "U", "V", "W", "X", "Y", "Z",
+impl fmt::Display for NvimAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NvimAction::Keys(k) => f.write_str(k),
+            NvimAction::Command(c) => write!(f, ":{c}"),
+            NvimAction::Function(_) => f.write_str("<function>"),
+            NvimAction::Lua(chunk) => write!(f, ":lua {chunk}"),
+            NvimAction::Sequence(actions) => {
+                let actions: Vec<String> = actions.iter().map(|a| a.to_string()).collect();
+                f.write_str(&actions.join("; "))
+            }
+            NvimAction::Conditional { condition, then, otherwise } => {
+                write!(f, "{then} if {condition}")?;
+                if let Some(otherwise) = otherwise {
+                    write!(f, ", else {otherwise}")?;
+                }
+                Ok(())
+            }
+            NvimAction::Plug(name) => write!(f, "<Plug>{name}"),
+            NvimAction::Submode(submode) => write!(f, "<{} mode>", submode.name),
+        }
+    }
+}
 
-    // Numbers
-    "0", "1", "2", "3", "4", "5", "6", "7", "8", "9",
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeymapKind {
+    /// Moves the cursor, also registered for operator-pending mode
+    Motion,
+    /// Waits for a motion (`d`, `y`)
+    Operator,
+    /// Only makes sense in normal/visual mode
+    #[default]
+    Action,
+}
 
-    // Special characters
-    "!", "@", "#", "$", "%", "^", "&", "*", "(", ")", "-", "_", "=", "+", "[", "]", "{", "}", ";", ":", "'", "\"", ",", ".", "/", "<", ">", "?", "`", "~",
+#[derive(Clone, Debug)]
+pub struct KeymapEntry {
+    pub action: NvimAction,
+    pub kind: KeymapKind,
+    /// Wrap the action in a cinnamon scroll. Not done in operator-pending mode,
+    /// the scroll is asynchronous and the operator needs the motion to finish first.
+    pub scroll: bool,
+    /// Function actions can be repeated with `.`, see `repeat`
+    pub repeatable: bool,
+    /// Passed as `desc` to nvim and shown in the which-key popup
+    pub desc: Option<String>,
+    /// Category the entry is listed under in the which-key popup
+    pub group: Option<String>,
+    /// Modes the entry is mapped in, empty for the defaults of its kind, see `target_modes`
+    pub modes: Vec<Mode>,
+}
 
-    // Function keys
-    "<F1>", "<F2>", "<F3>", "<F4>", "<F5>", "<F6>", "<F7>", "<F8>", "<F9>", "<F10>", "<F11>", "<F12>",
+impl KeymapEntry {
+    pub fn new(action: NvimAction) -> Self {
+        KeymapEntry {
+            action,
+            kind: KeymapKind::default(),
+            scroll: false,
+            repeatable: false,
+            desc: None,
+            group: None,
+            modes: Vec::new(),
+        }
+    }
 
-    // Navigation keys
-    "<Up>", "<Down>", "<Left>", "<Right>", "<Home>", "<End>", "<PageUp>", "<PageDown>",
+    pub fn kind(mut self, kind: KeymapKind) -> Self {
+        self.kind = kind;
+        self
+    }
 
-    // Editing keys
-    "<BS>", "<Del>", "<CR>", "<Tab>", "<Esc>", "<Space>",
+    pub fn scroll(mut self, scroll: bool) -> Self {
+        self.scroll = scroll;
+        self
+    }
 
-     // Special characters, escaped where necessary.
-    "<Bar>", "<Bslash>",
+    /// `repeat` in `nvim_keymap!`, none of the built-in maps use it
+    #[allow(dead_code)]
+    pub fn repeatable(mut self, repeatable: bool) -> Self {
+        self.repeatable = repeatable;
+        self
+    }
 
-    // Modifier keys + example character (x).  This needs to be expanded for all relevant characters.
-    //  This is the most significant expansion, as it's combinatorial.
-    "<C-a>", "<C-b>", "<C-c>", "<C-d>", "<C-e>", "<C-f>", "<C-g>", "<C-h>", "<C-i>", "<C-j>", "<C-k>", "<C-l>", "<C-m>",
-    "<C-n>", "<C-o>", "<C-p>", "<C-q>", "<C-r>", "<C-s>", "<C-t>", "<C-u>", "<C-v>", "<C-w>", "<C-x>", "<C-y>", "<C-z>",
-    "<C-0>", "<C-1>", "<C-2>", "<C-3>", "<C-4>", "<C-5>", "<C-6>", "<C-7>", "<C-8>", "<C-9>",
-    "<C-[>", "<C-]>",  // Control with brackets
-    "<C-Space>",
-    "<C-Up>", "<C-Down>", "<C-Left>", "<C-Right>",
+    pub fn desc(mut self, desc: impl Into<String>) -> Self {
+        self.desc = Some(desc.into());
+        self
+    }
 
-    // Mouse (basic)
-    "<LeftMouse>", "<RightMouse>", "<MiddleMouse>",
+    pub fn group(mut self, group: impl Into<String>) -> Self {
+        self.group = Some(group.into());
+        self
+    }
 
-    // Keypad (basic examples - add all as needed)
-    "<kHome>", "<k0>", "<k1>", "<k2>", "<k3>", "<k4>", "<k5>", "<k6>", "<k7>", "<k8>", "<k9>",
-    "<kPlus>", "<kMinus>", "<kMultiply>", "<kDivide>", "<kEnter>", "<kPoint>",
+    pub fn modes(mut self, modes: impl IntoIterator<Item = Mode>) -> Self {
+        self.modes = modes.into_iter().collect();
+        self
+    }
 
-    // Leader keys (placeholders, actual mapping is configuration-dependent)
-    "<Leader>", "<LocalLeader>",
-];
+    /// `modes`, by default normal and visual mode. Motions also work after an operator (`dk`, `yf`),
+    /// and in select mode if they start with a special key (`<C-...>`, `<Up>` etc.), typed
+    /// characters replace the selection there.
+    pub fn target_modes(&self, keys: &KeySequence) -> Vec<Mode> {
+        if !self.modes.is_empty() {
+            return self.modes.clone();
+        }
 
-fn clear_keymap(mode: Mode) -> Result<()> {
-    // Iterating over the maps can panic if there is a binding for a mode nvim-oxi doesn't support
-    _ = catch_unwind(|| {
-        for binding in api::get_keymap(mode) {
-            if binding.lhs.starts_with("<Plug>") {
-                continue;
+        let mut modes = vec![Mode::Normal, Mode::Visual];
+        if self.kind == KeymapKind::Motion {
+            modes.push(Mode::OperatorPending);
+            if keys.keys().first().is_some_and(|key| key.as_char().is_none()) {
+                modes.push(Mode::Select);
             }
-            _ = api::del_keymap(mode, &binding.lhs);
         }
-    });
+        modes
+    }
+
+    /// The description, or what the action does if there is none
+    pub fn description(&self) -> String {
+        if let Some(desc) = &self.desc {
+            return desc.clone();
+        }
+        self.action.to_string()
+    }
+
+    /// The action as it is mapped, wrapped in a scroll and made repeatable if requested
+    pub fn mapped_action(&self) -> NvimAction {
+        let action = if self.scroll {
+            NvimAction::Function(wrap_action(self.action.clone()))
+        } else {
+            self.action.clone()
+        };
+
+        match action {
+            NvimAction::Function(func) if self.repeatable => NvimAction::Function(repeat::repeatable(func)),
+            action => action,
+        }
+    }
+}
+
+/// `action  [kind, scroll, repeat, modes]  - description`, the kind only if it isn't the default
+impl fmt::Display for KeymapEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.action)?;
+
+        let mut flags = Vec::new();
+        if self.kind != KeymapKind::default() {
+            flags.push(format!("{:?}", self.kind).to_lowercase());
+        }
+        if self.scroll {
+            flags.push("scroll".to_string());
+        }
+        if self.repeatable {
+            flags.push("repeat".to_string());
+        }
+        if !self.modes.is_empty() {
+            let modes: Vec<&str> = self.modes.iter().filter_map(|mode| mode_name(*mode)).collect();
+            flags.push(modes.join("/"));
+        }
+        if !flags.is_empty() {
+            write!(f, "  [{}]", flags.join(", "))?;
+        }
+        if let Some(desc) = &self.desc {
+            write!(f, "  - {desc}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Lhs -> entry, ordered by lhs
+#[derive(Clone, Default)]
+pub struct NvimKeymap(BTreeMap<KeySequence, KeymapEntry>);
+
+impl NvimKeymap {
+    pub fn new() -> Self {
+        NvimKeymap::default()
+    }
+
+    /// Replaces and returns the previous entry for `keys`
+    pub fn insert(&mut self, keys: KeySequence, entry: KeymapEntry) -> Option<KeymapEntry> {
+        self.0.insert(keys, entry)
+    }
+
+    #[allow(dead_code)]
+    pub fn remove(&mut self, keys: &KeySequence) -> Option<KeymapEntry> {
+        self.0.remove(keys)
+    }
+
+    pub fn get(&self, keys: &KeySequence) -> Option<&KeymapEntry> {
+        self.0.get(keys)
+    }
+
+    pub fn contains_key(&self, keys: &KeySequence) -> bool {
+        self.0.contains_key(keys)
+    }
+
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &KeySequence> {
+        self.0.keys()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&KeySequence, &KeymapEntry)> {
+        self.0.iter()
+    }
+
+    /// Adds the entries of `other` that aren't mapped yet
+    pub fn merge(&mut self, other: NvimKeymap) {
+        for (keys, entry) in other {
+            self.0.entry(keys).or_insert(entry);
+        }
+    }
+
+    /// Adds the entries of `other`, replacing the ones mapped already
+    pub fn override_with(&mut self, other: NvimKeymap) {
+        self.0.extend(other.0);
+    }
+}
+
+impl FromIterator<(KeySequence, KeymapEntry)> for NvimKeymap {
+    fn from_iter<I: IntoIterator<Item = (KeySequence, KeymapEntry)>>(entries: I) -> Self {
+        NvimKeymap(entries.into_iter().collect())
+    }
+}
+
+impl IntoIterator for NvimKeymap {
+    type Item = (KeySequence, KeymapEntry);
+    type IntoIter = std::collections::btree_map::IntoIter<KeySequence, KeymapEntry>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a NvimKeymap {
+    type Item = (&'a KeySequence, &'a KeymapEntry);
+    type IntoIter = std::collections::btree_map::Iter<'a, KeySequence, KeymapEntry>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+impl fmt::Debug for NvimKeymap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(self.0.iter().map(|(keys, entry)| (keys.to_string(), entry)))
+            .finish()
+    }
+}
+
+/// One `lhs  entry` line per mapping
+impl fmt::Display for NvimKeymap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (keys, entry) in &self.0 {
+            writeln!(f, "{:<12} {entry}", keys.to_string())?;
+        }
+        Ok(())
+    }
+}
+
+/// Owner of the global keymaps in the registry
+const GLOBAL_OWNER: &str = "global";
+
+/// Keymaps registered by `setup_keymap` / `setup_buf_keymap`, by mode, buffer and owner
+/// (`"lsp"`, `"buffer"`), so one owner's maps can be removed without touching the others
+#[derive(Default)]
+pub struct KeymapRegistry(Vec<(Mode, Option<i32>, &'static str, NvimKeymap)>);
+
+impl KeymapRegistry {
+    fn register(&mut self, mode: Mode, buffer: Option<i32>, owner: &'static str, keymap: &NvimKeymap) {
+        self.0.retain(|(_, buffer, _, _)| buffer.is_none_or(|handle| Buffer::from(handle).is_valid()));
+
+        match self.0.iter_mut().find(|(m, b, o, _)| *m == mode && *b == buffer && *o == owner) {
+            Some((_, _, _, registered)) => registered.override_with(keymap.clone()),
+            None => self.0.push((mode, buffer, owner, keymap.clone())),
+        }
+    }
+
+    fn unregister(&mut self, mode: Mode, buffer: Option<i32>, owner: &str) -> Option<NvimKeymap> {
+        let index = self.0.iter().position(|(m, b, o, _)| *m == mode && *b == buffer && *o == owner)?;
+        Some(self.0.remove(index).3)
+    }
+
+    /// Mode, buffer handle for buffer-local maps, owner and keymap
+    pub fn iter(&self) -> impl Iterator<Item = (Mode, Option<i32>, &'static str, &NvimKeymap)> {
+        self.0.iter().map(|(mode, buffer, owner, keymap)| (*mode, *buffer, *owner, keymap))
+    }
+
+    /// Buffer handle and keymap for `mode`
+    pub fn by_mode(&self, mode: Mode) -> impl Iterator<Item = (Option<i32>, &NvimKeymap)> {
+        self.iter().filter(move |(m, _, _, _)| *m == mode).map(|(_, buffer, _, keymap)| (buffer, keymap))
+    }
 
-    for key in ALL_KEYS {
-        api::set_keymap(mode, key, "", &SetKeymapOpts::default())?;
+    /// Lhs the owners of `mode` and `buffer` map
+    fn keys(&self, mode: Mode, buffer: Option<i32>) -> Vec<KeySequence> {
+        self.iter()
+            .filter(|(m, b, _, _)| *m == mode && *b == buffer)
+            .flat_map(|(_, _, _, keymap)| keymap.keys().cloned())
+            .collect()
     }
+}
+
+thread_local! {
+    static REGISTRY: RefCell<KeymapRegistry> = RefCell::new(KeymapRegistry::default());
+}
+
+pub fn with_registry<T>(f: impl FnOnce(&KeymapRegistry) -> T) -> T {
+    REGISTRY.with_borrow(f)
+}
+
+pub fn clear_registry() {
+    REGISTRY.take();
+}
+
+/// Used by `nvim_keymap!`, reports lhs that are given twice instead of silently keeping the last
+pub fn keymap_from_entries(entries: Vec<(String, KeymapEntry)>) -> NvimKeymap {
+    keymap_lint::check_definitions(entries.iter().map(|(keys, _)| keys.as_str()));
+    entries.into_iter()
+        .map(|(keys, entry)| (KeySequence::from(keys), entry))
+        .collect()
+}
+
+/// Modes keymaps are split into, in setup order
+const MODES: [Mode; 7] = [
+    Mode::Normal,
+    Mode::Visual,
+    Mode::Select,
+    Mode::OperatorPending,
+    Mode::Insert,
+    Mode::CmdLine,
+    Mode::Terminal,
+];
+
+/// The entries of `keymap` per mode they are mapped in, see `KeymapEntry::target_modes`
+pub fn keymaps_by_mode(keymap: &NvimKeymap) -> Vec<(Mode, NvimKeymap)> {
+    MODES.iter()
+        .filter_map(|&mode| {
+            let mode_keymap: NvimKeymap = keymap.iter()
+                .filter(|(keys, entry)| entry.target_modes(keys).contains(&mode))
+                .map(|(keys, entry)| match mode {
+                    Mode::OperatorPending => (keys.clone(), entry.clone().scroll(false)),
+                    _ => (keys.clone(), entry.clone()),
+                })
+                .collect();
+            (!mode_keymap.is_empty()).then_some((mode, mode_keymap))
+        })
+        .collect()
+}
+
+pub fn parse_mode(name: &str) -> Option<Mode> {
+    Some(match name {
+        "n" => Mode::Normal,
+        "v" => Mode::Visual,
+        "s" => Mode::Select,
+        "o" => Mode::OperatorPending,
+        "i" => Mode::Insert,
+        "c" => Mode::CmdLine,
+        "t" => Mode::Terminal,
+        _ => return None,
+    })
+}
+
+/// Inverse of `parse_mode`
+pub fn mode_name(mode: Mode) -> Option<&'static str> {
+    Some(match mode {
+        Mode::Normal => "n",
+        Mode::Visual => "v",
+        Mode::Select => "s",
+        Mode::OperatorPending => "o",
+        Mode::Insert => "i",
+        Mode::CmdLine => "c",
+        Mode::Terminal => "t",
+        _ => return None,
+    })
+}
+
+/// Feeds `keys` (in keymap notation) as if typed, `mode` as in `nvim_feedkeys`
+pub fn feedkeys(keys: &str, mode: &str) -> Result<()> {
+    let replace_termcodes: Function = lua_get_global_path("vim.api.nvim_replace_termcodes")?;
+    let feedkeys: Function = lua_get_global_path("vim.api.nvim_feedkeys")?;
+
+    let keys: mlua::String = replace_termcodes.call((keys, true, false, true))?;
+    feedkeys.call::<_, ()>((keys, mode, false))?;
 
     Ok(())
 }
 
-pub fn setup_keymap_clean(mode: Mode, keymap: NvimKeymap) -> Result<()> {
-    clear_keymap(mode)?;
+/// Deletes the maps of `mode` and blanks every key, except `<Plug>` maps and `preserved`.
+/// Keys of `keymap` are mapped right after and aren't blanked first.
+/// The deleted maps are in the snapshot for `:KeymapRestore`.
+fn clear_keymap(mode: Mode, preserved: &[KeySequence], keymap: &NvimKeymap, all_modifiers: bool) -> Result<()> {
+    keymap_snapshot::save(mode, None)?;
+
+    for map in current_maps(mode, None)? {
+        if map.lhs.starts_with("<Plug>") || preserved.contains(&KeySequence::from(map.lhs.as_str())) {
+            continue;
+        }
+        _ = api::del_keymap(mode, &map.lhs);
+    }
+
+    let keys: Vec<KeySequence> = all_keys(all_modifiers)
+        .into_iter()
+        .map(KeySequence::from)
+        .filter(|key| !preserved.contains(key) && !keymap.contains_key(key))
+        .collect();
+    for key in &keys {
+        let lhs = key.to_string();
+        reload::track_keymap(mode, &lhs, None);
+        api::set_keymap(mode, &lhs, "", &SetKeymapOpts::default())?;
+    }
+    keymap_lint::register(mode, None, &keys);
+
+    Ok(())
+}
+
+fn keymap_callback(context: String, func: KeymapFunction) -> impl Fn(()) + 'static {
+    move |()| {
+        let run = || (*func)(&KeymapContext::current()?);
+        if let Some(Err(e)) = panic_log::guard(&context, run) {
+            log_error!(title = "nvim-config: keymap", "Keybind function failed ({context}): {e}");
+        };
+    }
+}
+
+pub fn setup_keymap_clean(
+    mode: Mode,
+    keymap: NvimKeymap,
+    preserved: &[KeySequence],
+    all_modifiers: bool,
+) -> Result<()> {
+    // Blanking sets a map for every key of `all_keys`, about a thousand per mode with `all_modifiers`
+    let step = format!("clear_keymap {}", mode_name(mode).unwrap_or("?"));
+    profile::measure(&step, || clear_keymap(mode, preserved, &keymap, all_modifiers))?;
     setup_keymap(mode, keymap)?;
     Ok(())
 }
 
+/// Keys running `cmd`. `:` in normal, visual and operator-pending mode, where the command line
+/// gets the visual range or acts as a motion. Elsewhere `:` would be typed as text, `<Cmd>` runs
+/// the command without leaving the mode.
+fn command_keys(cmd: &str, colon: bool) -> String {
+    match colon {
+        true => format!(":{cmd}<CR>"),
+        false => format!("<Cmd>{cmd}<CR>"),
+    }
+}
+
+/// Rhs and options for mapping `entry` in `mode`, actions that aren't keys become a callback
+fn keymap_definition(context: String, mode: Mode, entry: &KeymapEntry) -> (String, SetKeymapOpts) {
+    let mut opts = SetKeymapOpts::builder();
+    opts.silent(true);
+    if let Some(desc) = &entry.desc {
+        opts.desc(desc);
+    }
+
+    let rhs = match entry.mapped_action() {
+        NvimAction::Keys(k) => {
+            opts.noremap(true);
+            k
+        }
+        NvimAction::Command(cmd) => {
+            opts.noremap(true);
+            command_keys(&cmd, matches!(mode, Mode::Normal | Mode::Visual | Mode::OperatorPending))
+        }
+        // Remapped, the `<Plug>` map is the point
+        NvimAction::Plug(name) => format!("<Plug>{name}"),
+        NvimAction::Function(func) => {
+            opts.callback(keymap_callback(context, func));
+            String::new()
+        }
+        action => {
+            opts.callback(keymap_callback(context, Rc::new(move |context| action.run(context))));
+            String::new()
+        }
+    };
+
+    (rhs, opts.build())
+}
+
 pub fn setup_keymap(mode: Mode, keymap: NvimKeymap) -> Result<()> {
-    for (binding, action) in keymap.into_iter() {
-        let rhs = match action {
-            NvimAction::Keys(k) => k,
-            NvimAction::Command(cmd) => format!(":{}<CR>", cmd),
-
-            NvimAction::Function(func) => {
-                api::set_keymap(
-                    mode, &binding, "",
-                    &SetKeymapOpts::builder()
-                        .silent(true)
-                        .callback(move |()| {
-                            if let Err(e) = (*func)() {
-                                nvim::print!("Keybind function failed: {e}");
-                            };
-                        })
-                        .build()
-                )?;
-                continue;
-            }
-        };
-        api::set_keymap(
-            mode, &binding, &rhs,
-            &SetKeymapOpts::builder()
-                .silent(true)
-                .noremap(true)
-                .build()
-        )?;
+    keymap_snapshot::save(mode, None)?;
+    keymap_lint::register(mode, None, keymap.keys());
+    REGISTRY.with_borrow_mut(|r| r.register(mode, None, GLOBAL_OWNER, &keymap));
+
+    for (binding, entry) in keymap.iter() {
+        let binding = binding.to_string();
+        reload::track_keymap(mode, &binding, None);
+        let (rhs, opts) = keymap_definition(format!("keymap {binding}"), mode, entry);
+        api::set_keymap(mode, &binding, &rhs, &opts)?;
     }
 
+    which_key::setup_prefixes(mode, None, &keymap)?;
+
     Ok(())
 }
 
-#[allow(dead_code)]
-fn clear_buf_keymap(buf: &mut Buffer, mode: Mode) -> Result<()> {
-    let buf_handle = buf.handle();
-
-    // This panics no matter what, don't really know why catch_unwind doesn't help
-    _ = catch_unwind(|| {
-        let mut buf = Buffer::from(buf_handle);
-
-         if let Ok(keymap) = buf.get_keymap(mode) {
-             for binding in keymap {
-                 if binding.lhs.starts_with("<Plug>") {
-                     continue;
-                 }
-                 _ = buf.del_keymap(mode, &binding.lhs);
-             }
-         }
-    });
+/// Deletes the buffer-local maps of `mode` that `filter` selects, `<Plug>` maps are kept.
+/// The deleted maps are in the snapshot for `:KeymapRestore`.
+pub fn clear_buf_keymap(buf: &mut Buffer, mode: Mode, filter: impl Fn(&SavedMap) -> bool) -> Result<()> {
+    keymap_snapshot::save(mode, Some(buf.handle()))?;
+
+    for map in current_maps(mode, Some(buf.handle()))? {
+        if !map.lhs.starts_with("<Plug>") && filter(&map) {
+            _ = buf.del_keymap(mode, &map.lhs);
+        }
+    }
 
     Ok(())
 }
 
-pub fn setup_buf_keymap(buf: &mut Buffer, mode: Mode, keymap: NvimKeymap) -> Result<()> {
-    for (binding, action) in keymap.into_iter() {
-        let rhs = match action {
-            NvimAction::Keys(k) => k,
-            NvimAction::Command(cmd) => format!(":{}<CR>", cmd),
-
-            NvimAction::Function(func) => {
-                buf.set_keymap(
-                    mode, &binding, "",
-                    &SetKeymapOpts::builder()
-                        .silent(true)
-                        .callback(move |()| {
-                            if let Err(e) = (*func)() {
-                                nvim::print!("Keybind function failed: {e}");
-                            };
-                        })
-                        .build()
-                )?;
-                continue;
-            }
-        };
-        buf.set_keymap(
-            mode, &binding, &rhs,
-            &SetKeymapOpts::builder()
-                .silent(true)
-                .noremap(true)
-                .build()
-        )?;
+/// Removes what `setup_buf_keymap` mapped in `buf` for `mode` on behalf of `owner`, and the
+/// which-key prefixes no other owner's maps continue
+pub fn remove_buf_keymap(buf: &mut Buffer, mode: Mode, owner: &str) -> Result<()> {
+    let handle = buf.handle();
+    let Some(keymap) = REGISTRY.with_borrow_mut(|r| r.unregister(mode, Some(handle), owner)) else {
+        return Ok(());
+    };
+    let remaining = REGISTRY.with_borrow(|r| r.keys(mode, Some(handle)));
+    keymap_lint::unregister(mode, Some(handle));
+    keymap_lint::register(mode, Some(handle), &remaining);
+
+    clear_buf_keymap(buf, mode, |map| {
+        let keys = KeySequence::from(map.lhs.as_str());
+        match map.desc.as_deref() == Some(which_key::PREFIX_DESC) {
+            true => !remaining.iter().any(|k| k.len() > keys.len() && k.starts_with(&keys)),
+            false => keymap.contains_key(&keys) && !remaining.contains(&keys),
+        }
+    })
+}
+
+/// `owner` names who maps the keymap, for `remove_buf_keymap`
+pub fn setup_buf_keymap(buf: &mut Buffer, mode: Mode, owner: &'static str, keymap: NvimKeymap) -> Result<()> {
+    keymap_snapshot::save(mode, Some(buf.handle()))?;
+    keymap_lint::register(mode, Some(buf.handle()), keymap.keys());
+    REGISTRY.with_borrow_mut(|r| r.register(mode, Some(buf.handle()), owner, &keymap));
+
+    for (binding, entry) in keymap.iter() {
+        let binding = binding.to_string();
+        reload::track_keymap(mode, &binding, Some(buf.handle()));
+        let (rhs, opts) = keymap_definition(format!("buffer keymap {binding}"), mode, entry);
+        buf.set_keymap(mode, &binding, &rhs, &opts)?;
     }
 
+    which_key::setup_prefixes(mode, Some(buf), &keymap)?;
+
     Ok(())
 }
 
+/// One action of a keymap entry, e.g. `seq (["<C-w>v"]), ("TelescopeCall find_files")` splits
+/// and finds a file, `if (float) ("close") else ("noh")` closes a float or clears the highlight.
 #[macro_export]
 macro_rules! nvim_action {
+    (@condition filetype $filetype:expr) => {
+        $crate::keymap_remapping::Condition::Filetype($filetype.into())
+    };
+
+    (@condition lsp) => {
+        $crate::keymap_remapping::Condition::LspAttached
+    };
+
+    (@condition float) => {
+        $crate::keymap_remapping::Condition::FloatingWindow
+    };
+
+    (@otherwise) => {
+        None
+    };
+
+    (@otherwise $( $action:tt )+) => {
+        Some(Box::new(nvim_action!($( $action )+)))
+    };
+
     ([ $keys:expr ]) => {{
         use $crate::keymap_remapping::NvimAction;
         NvimAction::Keys($keys.into())
@@ -204,6 +800,32 @@ macro_rules! nvim_action {
         NvimAction::Function(wrap_action(nvim_action!($( $action )*)))
     }};
 
+    (lua $chunk:expr) => {{
+        use $crate::keymap_remapping::NvimAction;
+        NvimAction::Lua($chunk.into())
+    }};
+
+    (plug $name:expr) => {{
+        use $crate::keymap_remapping::NvimAction;
+        NvimAction::Plug($name.into())
+    }};
+
+    // `seq (["keys"]), ("command"), ...`
+    (seq $( ( $( $action:tt )* ) ),+ $(,)?) => {{
+        use $crate::keymap_remapping::NvimAction;
+        NvimAction::Sequence(vec![ $( nvim_action!($( $action )*) ),+ ])
+    }};
+
+    // `if (filetype "rust" | lsp | float) (then) else (otherwise)`, the else branch is optional
+    (if ( $( $condition:tt )* ) ( $( $then:tt )* ) $( else ( $( $otherwise:tt )* ) )?) => {{
+        use $crate::keymap_remapping::NvimAction;
+        NvimAction::Conditional {
+            condition: nvim_action!(@condition $( $condition )*),
+            then: Box::new(nvim_action!($( $then )*)),
+            otherwise: nvim_action!(@otherwise $( $( $otherwise )* )?),
+        }
+    }};
+
     ($cmd:expr) => {{
         use $crate::keymap_remapping::NvimAction;
         NvimAction::Command($cmd.into())
@@ -212,25 +834,151 @@ macro_rules! nvim_action {
 
 #[macro_export]
 macro_rules! nvim_keymap {
+    (@mode n) => { $crate::nvim::api::types::Mode::Normal };
+    (@mode v) => { $crate::nvim::api::types::Mode::Visual };
+    (@mode s) => { $crate::nvim::api::types::Mode::Select };
+    (@mode o) => { $crate::nvim::api::types::Mode::OperatorPending };
+    (@mode i) => { $crate::nvim::api::types::Mode::Insert };
+    (@mode c) => { $crate::nvim::api::types::Mode::CmdLine };
+    (@mode t) => { $crate::nvim::api::types::Mode::Terminal };
+
+    // `[i, c]` maps the entry in exactly these modes
+    (@inner ( [ $( $mode:ident ),+ $(,)? ] $( $rest:tt )* )) => {{
+        let (keys, entry) = nvim_keymap!(@inner ( $( $rest )* ));
+        (keys, entry.modes([ $( nvim_keymap!(@mode $mode) ),+ ]))
+    }};
+
+    (@inner ( motion $( $rest:tt )* )) => {{
+        use $crate::keymap_remapping::KeymapKind;
+        let (keys, entry) = nvim_keymap!(@inner ( $( $rest )* ));
+        (keys, entry.kind(KeymapKind::Motion))
+    }};
+
+    (@inner ( operator $( $rest:tt )* )) => {{
+        use $crate::keymap_remapping::KeymapKind;
+        let (keys, entry) = nvim_keymap!(@inner ( $( $rest )* ));
+        (keys, entry.kind(KeymapKind::Operator))
+    }};
+
+    (@inner ( repeat $( $rest:tt )* )) => {{
+        let (keys, entry) = nvim_keymap!(@inner ( $( $rest )* ));
+        (keys, entry.repeatable(true))
+    }};
+
+    (@inner ( desc $desc:literal $( $rest:tt )* )) => {{
+        let (keys, entry) = nvim_keymap!(@inner ( $( $rest )* ));
+        (keys, entry.desc($desc))
+    }};
+
     (@inner ( $str:expr )) => {{
         use $crate::nvim_action;
-        ($str.to_string(), nvim_action!([ $str ]))
+        use $crate::keymap_remapping::KeymapEntry;
+        ($str.to_string(), KeymapEntry::new(nvim_action!([ $str ])))
     }};
 
     (@inner ( @ $str:expr )) => {{
         use $crate::nvim_action;
-        ($str.to_string(), nvim_action!(@ [ $str ]))
+        use $crate::keymap_remapping::KeymapEntry;
+        ($str.to_string(), KeymapEntry::new(nvim_action!([ $str ])).scroll(true))
+    }};
+
+    (@inner ( $str:expr => @ $( $action:tt )* )) => {{
+        use $crate::nvim_action;
+        use $crate::keymap_remapping::KeymapEntry;
+        ($str.to_string(), KeymapEntry::new(nvim_action!($( $action )*)).scroll(true))
     }};
 
     (@inner ( $str:expr => $( $action:tt )* )) => {{
         use $crate::nvim_action;
-        ($str.to_string(), nvim_action!($( $action )*))
+        use $crate::keymap_remapping::KeymapEntry;
+        ($str.to_string(), KeymapEntry::new(nvim_action!($( $action )*)))
     }};
 
+    // `{ "Group" sticky "lhs": (...), (...) }` maps lhs to a submode with the entries
+    (@item { $group:literal sticky $lhs:literal : $( $item:tt ),* $(,)? }) => {
+        vec![ {
+            use $crate::keymap_remapping::{KeymapEntry, NvimAction};
+            use $crate::submode::Submode;
+            let keymap = nvim_keymap![ { $group: $( $item ),* } ];
+            let entry = KeymapEntry::new(NvimAction::Submode(Submode::new($group, keymap)));
+            ($lhs.to_string(), entry.group($group).desc(concat!($group, " mode")))
+        } ]
+    };
+
+    // `{ "Group": (...), (...) }` puts the entries in a which-key group
+    (@item { $group:literal : $( $item:tt ),* $(,)? }) => {
+        vec![
+            $( {
+                let (keys, entry) = nvim_keymap!(@inner $item);
+                (keys, entry.group($group))
+            } ),*
+        ]
+    };
+
+    (@item $item:tt) => {
+        vec![ nvim_keymap!(@inner $item) ]
+    };
+
     ($( $item:tt ),* $(,)?) => {{
-        use $crate::keymap_remapping::NvimKeymap;
-        NvimKeymap::from([
-            $( nvim_keymap!(@inner $item) ),*
-        ])
+        use $crate::keymap_remapping::keymap_from_entries;
+        keymap_from_entries([
+            $( nvim_keymap!(@item $item) ),*
+        ].concat())
     }};
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nvim_action;
+
+    fn with_count(count: u32) -> KeymapContext {
+        KeymapContext {
+            count,
+            register: "\"".to_string(),
+            mode: "n".to_string(),
+            buffer: Buffer::from(0),
+        }
+    }
+
+    #[test]
+    fn count_goes_before_keys() {
+        assert_eq!(with_count(3).apply_count("j"), "3j");
+        assert_eq!(with_count(2).apply_count("<C-w>h"), "2<C-w>h");
+    }
+
+    #[test]
+    fn no_count_keeps_keys() {
+        assert_eq!(with_count(0).apply_count("j"), "j");
+        assert_eq!(with_count(0).apply_count("20j"), "20j");
+    }
+
+    #[test]
+    fn existing_count_is_multiplied() {
+        assert_eq!(with_count(5).apply_count("20j"), "100j");
+        assert_eq!(with_count(2).apply_count("3dd"), "6dd");
+        assert_eq!(with_count(u32::MAX).apply_count("2j"), format!("{}j", u32::MAX));
+    }
+
+    #[test]
+    fn leading_zero_is_a_motion() {
+        // `0` goes to the start of the line, `50` would be a count
+        assert_eq!(with_count(5).apply_count("0"), "0");
+        assert_eq!(with_count(5).apply_count("0w"), "0w");
+    }
+
+    #[test]
+    fn describes_composed_actions() {
+        let split_and_find = nvim_action!(seq (["<C-w>v"]), ("TelescopeCall find_files"));
+        assert_eq!(split_and_find.to_string(), "<C-w>v; :TelescopeCall find_files");
+
+        let escape = nvim_action!(if (float) ("close") else ("noh"));
+        assert_eq!(escape.to_string(), ":close if floating window, else :noh");
+
+        let format_rust = nvim_action!(if (filetype "rust") ("RustFmt"));
+        assert_eq!(format_rust.to_string(), ":RustFmt if filetype rust");
+
+        let toggle = nvim_action!(lua "vim.diagnostic.enable(not vim.diagnostic.is_enabled())");
+        assert_eq!(toggle.to_string(), ":lua vim.diagnostic.enable(not vim.diagnostic.is_enabled())");
+    }
+}
@@ -0,0 +1,121 @@
+//! `:KeymapShow [mode] [sort=key|action|group] [filter]` lists what `setup_keymap` /
+//! `setup_buf_keymap` registered, to compare with what `:map` shows.
+//! The filter is matched against the lhs, the action and the description.
+
+use crate::{
+    Result, log_warn, panic_log,
+    key::KeySequence,
+    keymap_remapping::{parse_mode, with_registry, KeymapEntry},
+    nvim::api::{
+        self,
+        opts::CreateCommandOpts,
+        types::{CommandArgs, CommandNArgs, Mode},
+    },
+    scratch::show_scratch,
+    which_key::DEFAULT_GROUP,
+};
+
+#[derive(Clone, Copy, Default)]
+enum SortBy {
+    #[default]
+    Key,
+    Action,
+    Group,
+}
+
+#[derive(Default)]
+struct ShowArgs {
+    mode: Option<Mode>,
+    sort: SortBy,
+    filter: Option<String>,
+}
+
+fn parse_args(fargs: &[String]) -> std::result::Result<ShowArgs, String> {
+    let mut args = ShowArgs::default();
+
+    for arg in fargs {
+        if let Some(sort) = arg.strip_prefix("sort=") {
+            args.sort = match sort {
+                "key" => SortBy::Key,
+                "action" => SortBy::Action,
+                "group" => SortBy::Group,
+                _ => return Err(format!("Unknown sort order: {sort}")),
+            };
+        } else if let Some(mode) = parse_mode(arg).filter(|_| args.mode.is_none()) {
+            args.mode = Some(mode);
+        } else if args.filter.is_none() {
+            args.filter = Some(arg.to_lowercase());
+        } else {
+            return Err(format!("Unexpected argument: {arg}"));
+        }
+    }
+
+    Ok(args)
+}
+
+fn matches(filter: &str, keys: &KeySequence, entry: &KeymapEntry) -> bool {
+    [keys.to_string(), entry.action.to_string(), entry.desc.clone().unwrap_or_default()]
+        .iter()
+        .any(|text| text.to_lowercase().contains(filter))
+}
+
+fn render_keymaps(args: &ShowArgs) -> Vec<String> {
+    with_registry(|registry| {
+        let mut lines = Vec::new();
+
+        for (mode, buffer, owner, keymap) in registry.iter() {
+            if args.mode.is_some_and(|m| m != mode) {
+                continue;
+            }
+
+            let mut entries: Vec<(&KeySequence, &KeymapEntry)> = keymap
+                .iter()
+                .filter(|(keys, entry)| args.filter.as_deref().is_none_or(|f| matches(f, keys, entry)))
+                .collect();
+            if entries.is_empty() {
+                continue;
+            }
+            // Stable, so entries stay ordered by key within the same action or group
+            match args.sort {
+                SortBy::Key => {}
+                SortBy::Action => entries.sort_by_key(|(_, entry)| entry.action.to_string()),
+                SortBy::Group => {
+                    entries.sort_by_key(|&(_, entry)| entry.group.as_deref().unwrap_or(DEFAULT_GROUP))
+                }
+            }
+
+            if !lines.is_empty() {
+                lines.push(String::new());
+            }
+            lines.push(match buffer {
+                Some(handle) => format!("{mode:?} (buffer {handle}, {owner})"),
+                None => format!("{mode:?}"),
+            });
+            lines.extend(entries.iter().map(|(keys, entry)| format!("  {:<12} {entry}", keys.to_string())));
+        }
+
+        if lines.is_empty() {
+            lines.push("No matching keymaps".to_string());
+        }
+        lines
+    })
+}
+
+pub fn setup_show_command() -> Result<()> {
+    api::create_user_command(
+        "KeymapShow",
+        |args: CommandArgs| -> Result<()> {
+            panic_log::guard("command KeymapShow", || match parse_args(&args.fargs) {
+                Ok(args) => show_scratch("nvim-config://keymaps", render_keymaps(&args)),
+                Err(e) => {
+                    log_warn!("{e}\nUsage: KeymapShow [mode] [sort=key|action|group] [filter]");
+                    Ok(())
+                }
+            })
+            .unwrap_or(Ok(()))
+        },
+        &CreateCommandOpts::builder().nargs(CommandNArgs::Any).build(),
+    )?;
+
+    Ok(())
+}
@@ -0,0 +1,193 @@
+//! The keymaps as they were before the config mapped over them, and `:KeymapRestore` to go back
+//! to them, e.g. to hand the session to someone used to stock Vim. `:ConfigReload` applies the
+//! config again.
+//!
+//! `clear_keymap`, `setup_keymap` and `setup_buf_keymap` save the maps of a mode (and buffer)
+//! the first time they touch it in a setup run. A map keeps the version saved first, so the
+//! snapshot still has the original maps after `:ConfigReload`.
+
+use crate::{
+    Result, buffer_keymap, keymap_lint, keymap_remapping, log_info, log_warn, panic_log, plugins, reload,
+    keymap_remapping::mode_name,
+    mlua::{self, Function, Table},
+    nvim::api::{
+        self, Buffer,
+        opts::CreateCommandOpts,
+        types::{CommandArgs, Mode},
+    },
+    nvim_helper::lua::lua_get_global_path,
+};
+
+use std::{cell::RefCell, collections::{BTreeMap, HashSet}};
+
+/// A map as `nvim_get_keymap` describes it
+pub struct SavedMap {
+    /// Modes the map is defined for, not the mode it was queried with: `" "` for `:map`,
+    /// `"!"` for `:map!`, otherwise mode characters (`"x"`, `"nox"`)
+    mode: String,
+    pub lhs: String,
+    rhs: String,
+    callback: Option<Function<'static>>,
+    noremap: bool,
+    silent: bool,
+    expr: bool,
+    nowait: bool,
+    script: bool,
+    pub desc: Option<String>,
+}
+
+impl SavedMap {
+    fn from_table(map: Table<'static>) -> mlua::Result<SavedMap> {
+        let flag = |name: &str| -> mlua::Result<bool> { Ok(map.get::<_, Option<i64>>(name)?.unwrap_or(0) != 0) };
+
+        Ok(SavedMap {
+            mode: map.get("mode")?,
+            lhs: map.get("lhs")?,
+            rhs: map.get::<_, Option<String>>("rhs")?.unwrap_or_default(),
+            callback: map.get("callback")?,
+            noremap: flag("noremap")?,
+            silent: flag("silent")?,
+            expr: flag("expr")?,
+            nowait: flag("nowait")?,
+            script: flag("script")?,
+            desc: map.get("desc")?,
+        })
+    }
+
+    /// The modes of `mode` as `nvim_set_keymap` takes them, one map each
+    fn set_modes(&self) -> Vec<String> {
+        match self.mode.as_str() {
+            " " => vec![String::new()],
+            "!" => vec!["!".to_string()],
+            modes => modes.chars().map(String::from).collect(),
+        }
+    }
+
+    fn set(&self, buffer: Option<i32>) -> Result<()> {
+        for mode in self.set_modes() {
+            self.set_mode(&mode, buffer)?;
+        }
+        Ok(())
+    }
+
+    fn set_mode(&self, mode: &str, buffer: Option<i32>) -> Result<()> {
+        let opts = mlua::lua().create_table()?;
+        opts.set("noremap", self.noremap)?;
+        opts.set("silent", self.silent)?;
+        opts.set("expr", self.expr)?;
+        opts.set("nowait", self.nowait)?;
+        opts.set("script", self.script)?;
+        opts.set("desc", self.desc.clone())?;
+        opts.set("callback", self.callback.clone())?;
+
+        match buffer {
+            Some(handle) => {
+                let set_keymap: Function = lua_get_global_path("vim.api.nvim_buf_set_keymap")?;
+                set_keymap.call::<_, ()>((handle, mode, self.lhs.as_str(), self.rhs.as_str(), opts))?;
+            }
+            None => {
+                let set_keymap: Function = lua_get_global_path("vim.api.nvim_set_keymap")?;
+                set_keymap.call::<_, ()>((mode, self.lhs.as_str(), self.rhs.as_str(), opts))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Maps of `mode`, buffer-local ones for `buffer`. Read through Lua, nvim-oxi panics on maps
+/// for modes it doesn't know, and its map info has no callbacks.
+pub fn current_maps(mode: Mode, buffer: Option<i32>) -> Result<Vec<SavedMap>> {
+    let Some(name) = mode_name(mode) else {
+        return Ok(Vec::new());
+    };
+
+    let maps: Vec<Table<'static>> = match buffer {
+        Some(handle) => {
+            let get_keymap: Function = lua_get_global_path("vim.api.nvim_buf_get_keymap")?;
+            get_keymap.call((handle, name))?
+        }
+        None => {
+            let get_keymap: Function = lua_get_global_path("vim.api.nvim_get_keymap")?;
+            get_keymap.call(name)?
+        }
+    };
+
+    let maps = maps.into_iter().map(SavedMap::from_table).collect::<mlua::Result<Vec<_>>>()?;
+    Ok(maps)
+}
+
+#[derive(Default)]
+struct Snapshot {
+    /// Modes of the map, buffer handle for buffer-local maps and lhs -> the first saved map.
+    /// A `:map` shows up for n, v and o and is saved once.
+    maps: BTreeMap<(String, Option<i32>, String), SavedMap>,
+    /// Modes and buffers saved in this setup run, after that they have the config's maps
+    saved: HashSet<(&'static str, Option<i32>)>,
+}
+
+thread_local! {
+    static SNAPSHOT: RefCell<Snapshot> = RefCell::new(Snapshot::default());
+}
+
+/// Saves the maps of `mode` and `buffer`, unless they were saved in this setup run already
+pub fn save(mode: Mode, buffer: Option<i32>) -> Result<()> {
+    let Some(name) = mode_name(mode) else {
+        return Ok(());
+    };
+    if SNAPSHOT.with_borrow(|s| s.saved.contains(&(name, buffer))) {
+        return Ok(());
+    }
+
+    let maps = current_maps(mode, buffer)?;
+    SNAPSHOT.with_borrow_mut(|snapshot| {
+        snapshot.saved.insert((name, buffer));
+        for map in maps {
+            snapshot.maps.entry((map.mode.clone(), buffer, map.lhs.clone())).or_insert(map);
+        }
+    });
+
+    Ok(())
+}
+
+/// Called before the setup runs again, the maps it sees are the original ones again
+pub fn start_run() {
+    SNAPSHOT.with_borrow_mut(|s| s.saved.clear());
+}
+
+/// Removes the config's maps and puts the saved ones back
+fn restore() -> Result<()> {
+    // Otherwise new buffers and LSP clients get the config's maps again
+    buffer_keymap::remove_buffer_keymap_autocmds();
+    plugins::lsp::disable_keymaps();
+    reload::teardown_keymaps();
+    keymap_remapping::clear_registry();
+    keymap_lint::clear();
+
+    // Kept, so restoring again after `:ConfigReload` still goes back to the original maps
+    let failed = SNAPSHOT.with_borrow_mut(|snapshot| {
+        snapshot.maps.retain(|(_, buffer, _), _| buffer.is_none_or(|handle| Buffer::from(handle).is_valid()));
+        snapshot.maps.iter()
+            .filter(|((_, buffer, _), map)| map.set(*buffer).is_err())
+            .count()
+    });
+
+    match failed {
+        0 => log_info!("Keymaps restored, :ConfigReload applies the config again"),
+        n => log_warn!("Keymaps restored, {n} could not be set again"),
+    }
+
+    Ok(())
+}
+
+pub fn setup_restore_command() -> Result<()> {
+    api::create_user_command(
+        "KeymapRestore",
+        |_: CommandArgs| -> Result<()> {
+            panic_log::guard("command KeymapRestore", restore).unwrap_or(Ok(()))
+        },
+        &CreateCommandOpts::builder().build(),
+    )?;
+
+    Ok(())
+}
@@ -0,0 +1,206 @@
+use crate::{
+    key::{Key, KeySequence},
+    keymap_remapping::{KeymapEntry, KeymapKind, NvimAction, NvimKeymap},
+    log_warn,
+};
+
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+/// Either a built-in layout name or a custom `physical = "logical"` table
+#[derive(Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum LayoutConfig {
+    Named(String),
+    Custom(BTreeMap<String, String>),
+}
+
+/// Maps physical keys to the stock Vim keys they act as
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Layout {
+    /// (physical, logical)
+    keys: Vec<(char, char)>,
+}
+
+const DEFAULT_LAYOUT: &str = "shifted";
+
+/// Layouts `Layout::named` knows
+pub const LAYOUT_NAMES: &[&str] = &["qwerty", "shifted", "colemak", "dvorak"];
+
+impl Layout {
+    pub fn named(name: &str) -> Option<Layout> {
+        let keys: &[(char, char)] = match name {
+            "qwerty" => &[('h', 'h'), ('j', 'j'), ('k', 'k'), ('l', 'l')],
+            // Movement on the home row resting position, h is left free
+            "shifted" => &[('j', 'h'), ('k', 'j'), ('l', 'k'), (';', 'l')],
+            // Movement on hnei, displaced keys moved to the freed positions
+            "colemak" => &[
+                ('h', 'h'), ('n', 'j'), ('e', 'k'), ('i', 'l'),
+                ('k', 'n'), ('j', 'e'), ('l', 'i'),
+            ],
+            // Movement on dhtn (the qwerty hjkl positions)
+            "dvorak" => &[
+                ('d', 'h'), ('h', 'j'), ('t', 'k'), ('n', 'l'),
+                ('j', 'd'), ('k', 't'), ('l', 'n'),
+            ],
+            _ => return None,
+        };
+
+        Some(Layout { keys: keys.to_vec() })
+    }
+
+    pub fn custom(table: &BTreeMap<String, String>) -> Result<Layout, String> {
+        let single_char = |s: &str| {
+            let mut chars = s.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => Ok(c),
+                _ => Err(format!("Layout keys have to be single characters, got '{s}'")),
+            }
+        };
+
+        let keys = table
+            .iter()
+            .map(|(physical, logical)| Ok((single_char(physical)?, single_char(logical)?)))
+            .collect::<Result<Vec<_>, String>>()?;
+
+        Ok(Layout { keys })
+    }
+
+    pub fn from_config(config: Option<&LayoutConfig>) -> Layout {
+        let layout = match config {
+            None => Ok(None),
+            Some(LayoutConfig::Named(name)) => match Layout::named(name) {
+                Some(layout) => Ok(Some(layout)),
+                None => Err(format!("Unknown layout: {name}")),
+            },
+            Some(LayoutConfig::Custom(table)) => Layout::custom(table).map(Some),
+        };
+
+        match layout {
+            Ok(Some(layout)) => layout,
+            Ok(None) => Layout::named(DEFAULT_LAYOUT).unwrap(),
+            Err(e) => {
+                log_warn!("{e}, using the {DEFAULT_LAYOUT} layout");
+                Layout::named(DEFAULT_LAYOUT).unwrap()
+            }
+        }
+    }
+
+    /// Physical key that acts as `logical`. Uppercase letters move with their lowercase key,
+    /// as long as that is a letter too (`J` is `K` in the shifted layout, `L` stays).
+    pub fn physical(&self, logical: char) -> char {
+        if let Some((physical, _)) = self.keys.iter().find(|(_, l)| *l == logical) {
+            return *physical;
+        }
+
+        if !logical.is_ascii_uppercase() {
+            return logical;
+        }
+        match self.physical(logical.to_ascii_lowercase()) {
+            lowercase if lowercase.is_ascii_alphabetic() => lowercase.to_ascii_uppercase(),
+            _ => logical,
+        }
+    }
+
+    /// Rewrites typed characters of a logical lhs to the physical ones, special keys
+    /// and modified keys (`<C-w>`) are kept as is
+    pub fn transform_lhs(&self, lhs: &KeySequence) -> KeySequence {
+        lhs.keys()
+            .iter()
+            .map(|key| match key.as_char() {
+                Some(c) => Key::char(self.physical(c)),
+                None => *key,
+            })
+            .collect()
+    }
+
+    /// Physical keys acting as their logical counterparts, and in `<C-w>` chords. Only the
+    /// hjkl targets are motions, displaced keys (`i`, `d`) keep to normal and visual mode.
+    pub fn keymap(&self) -> NvimKeymap {
+        let mut keymap = NvimKeymap::new();
+
+        for (physical, logical) in &self.keys {
+            let entry = KeymapEntry::new(NvimAction::Keys(logical.to_string()));
+            let entry = match "hjkl".contains(*logical) {
+                true => entry.kind(KeymapKind::Motion).group("Movement"),
+                false => entry.group("Displaced keys"),
+            };
+            keymap.insert(KeySequence::from(Key::char(*physical)), entry);
+            keymap.insert(
+                KeySequence::from(format!("<C-w>{physical}")),
+                KeymapEntry::new(NvimAction::Keys(format!("<C-w>{logical}"))).group("Window focus"),
+            );
+        }
+
+        keymap
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(keys: &[(&str, &str)]) -> BTreeMap<String, String> {
+        keys.iter().map(|(p, l)| (p.to_string(), l.to_string())).collect()
+    }
+
+    #[test]
+    fn transforms_typed_characters() {
+        let layout = Layout::named("shifted").unwrap();
+        let lhs = layout.transform_lhs(&KeySequence::from("dh<C-w>l"));
+        assert_eq!(lhs, KeySequence::from("dj<C-w>;"));
+    }
+
+    #[test]
+    fn keeps_special_and_modified_keys() {
+        let layout = Layout::named("colemak").unwrap();
+        let lhs = KeySequence::from("<C-j><Space><Esc><M-k>");
+        assert_eq!(layout.transform_lhs(&lhs), lhs);
+    }
+
+    #[test]
+    fn qwerty_is_identity() {
+        let layout = Layout::named("qwerty").unwrap();
+        let lhs = KeySequence::from("hjkl<C-w>h");
+        assert_eq!(layout.transform_lhs(&lhs), lhs);
+    }
+
+    #[test]
+    fn custom_layout() {
+        let layout = Layout::custom(&table(&[("a", "h"), ("s", "j")])).unwrap();
+        assert_eq!(layout.physical('h'), 'a');
+        assert_eq!(layout.physical('j'), 's');
+        assert_eq!(layout.physical('k'), 'k');
+    }
+
+    #[test]
+    fn custom_layout_rejects_longer_keys() {
+        assert!(Layout::custom(&table(&[("ab", "h")])).is_err());
+        assert!(Layout::custom(&table(&[("a", "")])).is_err());
+    }
+
+    #[test]
+    fn uppercase_follows_letters() {
+        let layout = Layout::named("shifted").unwrap();
+        assert_eq!(layout.transform_lhs(&KeySequence::from("JKHL")), KeySequence::from("KLJL"));
+
+        let layout = Layout::named("dvorak").unwrap();
+        assert_eq!(layout.transform_lhs(&KeySequence::from("JK<C-J>")), KeySequence::from("HT<C-J>"));
+    }
+
+    #[test]
+    fn every_name_is_a_layout() {
+        assert!(LAYOUT_NAMES.iter().all(|name| Layout::named(name).is_some()));
+        assert!(Layout::named(DEFAULT_LAYOUT).is_some());
+    }
+
+    #[test]
+    fn only_hjkl_targets_are_motions() {
+        let keymap = Layout::named("colemak").unwrap().keymap();
+        let kind = |lhs: &str| keymap.get(&KeySequence::from(lhs)).map(|entry| entry.kind);
+
+        assert_eq!(kind("n"), Some(KeymapKind::Motion));
+        assert_eq!(kind("k"), Some(KeymapKind::Action));
+        assert_eq!(kind("l"), Some(KeymapKind::Action));
+    }
+}
@@ -1,6 +1,24 @@
+mod buffer_keymap;
+mod cheat_sheet;
+mod config;
 mod plugins;
+mod key;
 mod keymap;
+mod keymap_lint;
 mod keymap_remapping;
+mod keymap_show;
+mod keymap_snapshot;
+mod layout;
+mod logging;
+mod notify;
+mod panic_log;
+mod profile;
+mod reload;
+mod repeat;
+mod report;
+mod scratch;
+mod submode;
+mod which_key;
 
 pub use nvim_api_helper as nvim_helper;
 
@@ -11,19 +29,51 @@ use nvim_helper::{
 };
 
 use std::{
-    backtrace::Backtrace,
     env,
-    fs::{self, OpenOptions},
-    io::Write,
-    panic,
+    fs,
     path::PathBuf,
 };
 
 fn setup_config(_: ()) {
-    nvim::print!("Setting up nvim-config");
+    log_info!("Setting up nvim-config");
 
-    plugins::setup_plugins();
-    keymap::setup_keymaps();
+    profile::measure(profile::TOTAL_STEP, || {
+        let config = profile::measure("load_config", config::load_config);
+        logging::configure(config.log.as_ref());
+
+        if let Err(e) = report::setup_report() {
+            log_error!("Failed to setup startup report: {e}");
+        }
+        if let Err(e) = profile::setup_profile() {
+            log_error!("Failed to setup profiling: {e}");
+        }
+        if let Err(e) = panic_log::setup_panic_commands() {
+            log_error!("Failed to setup panic log commands: {e}");
+        }
+        if let Err(e) = logging::setup_log_commands() {
+            log_error!("Failed to setup log commands: {e}");
+        }
+        if let Err(e) = reload::setup_reload_command() {
+            log_error!("Failed to setup reload command: {e}");
+        }
+        if let Err(e) = keymap_lint::setup_lint_command() {
+            log_error!("Failed to setup keymap lint command: {e}");
+        }
+        if let Err(e) = keymap_show::setup_show_command() {
+            log_error!("Failed to setup keymap show command: {e}");
+        }
+        if let Err(e) = cheat_sheet::setup_export_command() {
+            log_error!("Failed to setup keymap export command: {e}");
+        }
+        if let Err(e) = keymap_snapshot::setup_restore_command() {
+            log_error!("Failed to setup keymap restore command: {e}");
+        }
+
+        profile::measure("setup_plugins", || plugins::setup_plugins(&config));
+        profile::measure("setup_keymaps", || keymap::setup_keymaps(&config));
+    });
+
+    report::print_summary();
 }
 
 pub fn nvim_dir() -> PathBuf {
@@ -42,34 +92,7 @@ fn nvim_config() -> nvim::Result<Dictionary> {
         return Ok(Dictionary::new());
     };
 
-    // Log panic to a file instead to stdout (for obvious reasons)
-    panic::set_hook(Box::new(move |_| {
-        let panic_path = nvim_dir.join("panic.log");
-        let panic_path = match panic_path.to_str() {
-            Some(path) => path,
-            None => {
-                nvim::print!("Invalid panic log path");
-                return;
-            },
-        };
-
-        nvim::print!("Panic occured ({panic_path})");
-
-        let backtrace = Backtrace::capture();
-        let mut file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(panic_path)
-            .inspect_err(|e| {
-                nvim::print!("Failed to open panic log file: {e}");
-            })
-            .expect("Failed to open panic log file");
-
-        _ = writeln!(file, "Panic occurred:\n{}", backtrace)
-            .inspect_err(|e| {
-                nvim::print!("Failed to write to panic file: {e}");
-            });
-    }));
+    panic_log::install_panic_hook();
 
     let mut res = Dictionary::new();
 
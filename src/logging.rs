@@ -0,0 +1,252 @@
+use crate::{
+    Result, notify, nvim_dir, panic_log,
+    nvim::{
+        self,
+        api::{
+            self,
+            opts::CreateCommandOpts,
+            types::{CommandArgs, CommandNArgs},
+        },
+    },
+    scratch::show_scratch,
+};
+
+use serde::Deserialize;
+use std::{
+    cell::Cell,
+    fmt,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const MAX_LOG_SIZE: u64 = 1024 * 1024;
+const KEPT_LOGS: usize = 1;
+const DEFAULT_TAIL_LINES: usize = 200;
+
+/// Ordered like `vim.log.levels`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    /// Value of the matching `vim.log.levels` entry
+    pub fn vim_level(self) -> i64 {
+        self as i64
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Level::Trace => "TRACE",
+            Level::Debug => "DEBUG",
+            Level::Info => "INFO",
+            Level::Warn => "WARN",
+            Level::Error => "ERROR",
+        })
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(match s.to_lowercase().as_str() {
+            "trace" => Level::Trace,
+            "debug" => Level::Debug,
+            "info" => Level::Info,
+            "warn" => Level::Warn,
+            "error" => Level::Error,
+            _ => return Err(format!("Invalid log level: {s}")),
+        })
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct LogConfig {
+    #[serde(default = "default_level")]
+    pub level: Level,
+    #[serde(default = "default_notify_level")]
+    pub notify_level: Level,
+}
+
+fn default_level() -> Level {
+    Level::Info
+}
+
+fn default_notify_level() -> Level {
+    Level::Warn
+}
+
+thread_local! {
+    static LEVEL: Cell<Level> = const { Cell::new(Level::Info) };
+    static NOTIFY_LEVEL: Cell<Level> = const { Cell::new(Level::Warn) };
+}
+
+pub fn configure(config: Option<&LogConfig>) {
+    LEVEL.set(config.map_or_else(default_level, |c| c.level));
+    NOTIFY_LEVEL.set(config.map_or_else(default_notify_level, |c| c.notify_level));
+}
+
+fn log_path() -> PathBuf {
+    nvim_dir().join("nvim-config.log")
+}
+
+/// UTC `YYYY-MM-DD HH:MM:SS`
+pub fn timestamp() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default();
+
+    let (days, rem) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+
+    // Days to civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}",
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+    )
+}
+
+fn write_line(level: Level, message: &str) -> std::io::Result<()> {
+    let path = log_path();
+    panic_log::rotate_if_needed(&path, MAX_LOG_SIZE, KEPT_LOGS);
+
+    let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+    writeln!(file, "{} [{level}] {message}", timestamp())
+}
+
+/// Writes to the log file and surfaces the message through `notify` above the notify level
+pub fn log_titled(level: Level, title: &str, message: &str) {
+    if level >= LEVEL.get() {
+        if let Err(e) = write_line(level, &format!("{title}: {message}")) {
+            nvim::print!("Failed to write log ({e}): {message}");
+        }
+    }
+
+    if level >= NOTIFY_LEVEL.get() {
+        notify::notify(level, title, message);
+    }
+}
+
+pub fn log(level: Level, message: &str) {
+    log_titled(level, notify::TITLE, message);
+}
+
+#[macro_export]
+macro_rules! log_error {
+    (title = $title:expr, $( $arg:tt )*) => {
+        $crate::logging::log_titled($crate::logging::Level::Error, $title, &format!($( $arg )*))
+    };
+    ($( $arg:tt )*) => {
+        $crate::logging::log($crate::logging::Level::Error, &format!($( $arg )*))
+    };
+}
+
+#[macro_export]
+macro_rules! log_warn {
+    (title = $title:expr, $( $arg:tt )*) => {
+        $crate::logging::log_titled($crate::logging::Level::Warn, $title, &format!($( $arg )*))
+    };
+    ($( $arg:tt )*) => {
+        $crate::logging::log($crate::logging::Level::Warn, &format!($( $arg )*))
+    };
+}
+
+#[macro_export]
+macro_rules! log_info {
+    (title = $title:expr, $( $arg:tt )*) => {
+        $crate::logging::log_titled($crate::logging::Level::Info, $title, &format!($( $arg )*))
+    };
+    ($( $arg:tt )*) => {
+        $crate::logging::log($crate::logging::Level::Info, &format!($( $arg )*))
+    };
+}
+
+#[macro_export]
+macro_rules! log_debug {
+    (title = $title:expr, $( $arg:tt )*) => {
+        $crate::logging::log_titled($crate::logging::Level::Debug, $title, &format!($( $arg )*))
+    };
+    ($( $arg:tt )*) => {
+        $crate::logging::log($crate::logging::Level::Debug, &format!($( $arg )*))
+    };
+}
+
+fn log_tail(count: usize) -> Vec<String> {
+    let Ok(content) = fs::read_to_string(log_path()) else {
+        return vec!["Log is empty".to_string()];
+    };
+
+    let lines: Vec<&str> = content.lines().collect();
+    lines[lines.len().saturating_sub(count)..]
+        .iter()
+        .map(|l| l.to_string())
+        .collect()
+}
+
+fn set_level(args: &[String]) {
+    let (cell, level) = match args {
+        [level] => (&LEVEL, level),
+        [target, level] if target == "notify" => (&NOTIFY_LEVEL, level),
+        _ => {
+            log_warn!("Usage: ConfigLogLevel [notify] {{trace|debug|info|warn|error}}");
+            return;
+        }
+    };
+
+    match level.parse() {
+        Ok(level) => cell.set(level),
+        Err(e) => log_warn!("{e}"),
+    }
+}
+
+pub fn setup_log_commands() -> Result<()> {
+    api::create_user_command(
+        "ConfigLog",
+        |args: CommandArgs| -> Result<()> {
+            panic_log::guard("command ConfigLog", || {
+                let count = args
+                    .fargs
+                    .first()
+                    .and_then(|c| c.parse().ok())
+                    .unwrap_or(DEFAULT_TAIL_LINES);
+                show_scratch("nvim-config://log", log_tail(count))
+            })
+            .unwrap_or(Ok(()))
+        },
+        &CreateCommandOpts::builder().nargs(CommandNArgs::ZeroOrOne).build(),
+    )?;
+
+    api::create_user_command(
+        "ConfigLogLevel",
+        |args: CommandArgs| -> Result<()> {
+            panic_log::guard("command ConfigLogLevel", || set_level(&args.fargs));
+            Ok(())
+        },
+        &CreateCommandOpts::builder().nargs(CommandNArgs::Any).build(),
+    )?;
+
+    Ok(())
+}
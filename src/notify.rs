@@ -0,0 +1,60 @@
+use crate::{
+    Result,
+    logging::Level,
+    mlua::Function,
+    nvim,
+    nvim_helper::{lua::lua_get_global_path, lua_value},
+};
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+pub const TITLE: &str = "nvim-config";
+
+/// Identical notifications within this window are only shown once
+const DEDUP_WINDOW: Duration = Duration::from_secs(10);
+
+thread_local! {
+    static RECENT: RefCell<HashMap<(Level, String, String), Instant>> = RefCell::new(HashMap::new());
+}
+
+fn is_duplicate(level: Level, title: &str, message: &str) -> bool {
+    let now = Instant::now();
+
+    RECENT.with_borrow_mut(|recent| {
+        recent.retain(|_, shown| now.duration_since(*shown) < DEDUP_WINDOW);
+
+        let key = (level, title.to_string(), message.to_string());
+        if recent.contains_key(&key) {
+            return true;
+        }
+        recent.insert(key, now);
+        false
+    })
+}
+
+fn vim_notify(level: Level, title: &str, message: &str) -> Result<()> {
+    let notify: Function = lua_get_global_path("vim.notify")?;
+    notify.call::<_, ()>((
+        message,
+        level.vim_level(),
+        lua_value!({
+            "title" => title,
+        }),
+    ))?;
+    Ok(())
+}
+
+/// Shows `message` through `vim.notify`, so notification plugins can pick it up
+pub fn notify(level: Level, title: &str, message: &str) {
+    if is_duplicate(level, title, message) {
+        return;
+    }
+
+    if vim_notify(level, title, message).is_err() {
+        nvim::print!("[{title}] {message}");
+    }
+}
@@ -0,0 +1,183 @@
+use crate::{
+    Result, logging, nvim_dir,
+    nvim::{
+        self,
+        api::{
+            self,
+            opts::CreateCommandOpts,
+            types::{CommandArgs, CommandNArgs},
+        },
+    },
+    log_error,
+    scratch::show_scratch,
+};
+
+use std::{
+    any::Any,
+    backtrace::Backtrace,
+    cell::RefCell,
+    fs::{self, OpenOptions},
+    io::Write,
+    panic::{self, AssertUnwindSafe, PanicHookInfo},
+    path::{Path, PathBuf},
+};
+
+/// Rotated once it grows past this size
+const MAX_LOG_SIZE: u64 = 512 * 1024;
+/// Number of rotated files kept (panic.log.1, panic.log.2, ...)
+const KEPT_LOGS: usize = 2;
+
+const ENTRY_START: &str = "=== panic";
+const DEFAULT_SHOWN_ENTRIES: usize = 5;
+
+thread_local! {
+    static CONTEXT: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Pops its context entry when dropped
+pub struct ContextGuard(());
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        CONTEXT.with_borrow_mut(|context| context.pop());
+    }
+}
+
+/// Marks what is currently running, recorded in the panic log if anything panics
+/// before the returned guard is dropped
+pub fn enter(context: impl Into<String>) -> ContextGuard {
+    CONTEXT.with_borrow_mut(|stack| stack.push(context.into()));
+    ContextGuard(())
+}
+
+/// Runs a callback invoked by Neovim, turning a panic into a reported error instead of
+/// unwinding across the FFI boundary. The panic hook still logs it as usual.
+pub fn guard<T>(context: &str, f: impl FnOnce() -> T) -> Option<T> {
+    let _context = enter(context);
+
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(value) => Some(value),
+        Err(payload) => {
+            log_error!("Panic in {context}: {}", panic_message(payload.as_ref()));
+            None
+        }
+    }
+}
+
+fn current_context() -> String {
+    CONTEXT.with(|context| match context.try_borrow() {
+        Ok(context) if context.is_empty() => "<none>".to_string(),
+        Ok(context) => context.join(" > "),
+        Err(_) => "<unavailable>".to_string(),
+    })
+}
+
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "<non-string panic payload>".to_string()
+    }
+}
+
+fn log_path() -> PathBuf {
+    nvim_dir().join("panic.log")
+}
+
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{n}"));
+    PathBuf::from(name)
+}
+
+pub fn rotate_if_needed(path: &Path, max_size: u64, kept: usize) {
+    let Ok(metadata) = fs::metadata(path) else {
+        return;
+    };
+    if metadata.len() < max_size {
+        return;
+    }
+
+    for n in (1..kept).rev() {
+        _ = fs::rename(rotated_path(path, n), rotated_path(path, n + 1));
+    }
+    if kept > 0 {
+        _ = fs::rename(path, rotated_path(path, 1));
+    } else {
+        _ = fs::remove_file(path);
+    }
+}
+
+fn write_entry(info: &PanicHookInfo) -> std::io::Result<()> {
+    let path = log_path();
+    rotate_if_needed(&path, MAX_LOG_SIZE, KEPT_LOGS);
+
+    let location = info
+        .location()
+        .map(|l| format!("{}:{}:{}", l.file(), l.line(), l.column()))
+        .unwrap_or_else(|| "<unknown>".to_string());
+
+    let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+
+    writeln!(file, "{ENTRY_START} {} ===", logging::timestamp())?;
+    writeln!(file, "message: {}", panic_message(info.payload()))?;
+    writeln!(file, "location: {location}")?;
+    writeln!(file, "context: {}", current_context())?;
+    writeln!(file, "backtrace:\n{}", Backtrace::capture())?;
+
+    Ok(())
+}
+
+pub fn install_panic_hook() {
+    // Log panic to a file instead to stdout (for obvious reasons)
+    panic::set_hook(Box::new(|info| {
+        nvim::print!("Panic occured, see :ConfigPanics ({})", log_path().display());
+
+        _ = write_entry(info).inspect_err(|e| {
+            nvim::print!("Failed to write to panic file: {e}");
+        });
+    }));
+}
+
+fn recent_entries(count: usize) -> Vec<String> {
+    let Ok(content) = fs::read_to_string(log_path()) else {
+        return vec!["No panics logged".to_string()];
+    };
+
+    let starts: Vec<usize> = content.match_indices(ENTRY_START).map(|(i, _)| i).collect();
+    let first = starts.len().saturating_sub(count);
+
+    let mut lines = Vec::new();
+    // Newest first
+    for (n, &start) in starts.iter().enumerate().skip(first).rev() {
+        let end = starts.get(n + 1).copied().unwrap_or(content.len());
+        lines.extend(content[start..end].lines().map(str::to_string));
+    }
+
+    if lines.is_empty() {
+        lines.push("No panics logged".to_string());
+    }
+    lines
+}
+
+pub fn setup_panic_commands() -> Result<()> {
+    api::create_user_command(
+        "ConfigPanics",
+        |args: CommandArgs| -> Result<()> {
+            guard("command ConfigPanics", || {
+                let count = args
+                    .fargs
+                    .first()
+                    .and_then(|c| c.parse().ok())
+                    .unwrap_or(DEFAULT_SHOWN_ENTRIES);
+                show_scratch("nvim-config://panics", recent_entries(count))
+            })
+            .unwrap_or(Ok(()))
+        },
+        &CreateCommandOpts::builder().nargs(CommandNArgs::ZeroOrOne).build(),
+    )?;
+
+    Ok(())
+}
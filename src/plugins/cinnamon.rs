@@ -1,32 +1,38 @@
 use crate::{
     Result,
     mlua::{self, Table, Function, Value},
-    nvim,
-    nvim_helper::{lua_value, lua_plugins::require_plugin},
+    log_error, panic_log, reload,
+    nvim_helper::{lua::lua_get_global_path, lua_value, lua_plugins::require_plugin},
 };
-use crate::keymap_remapping::{NvimAction, NvimKeymap};
+use crate::keymap_remapping::{KeymapFunction, NvimAction, NvimKeymap};
 
 use std::rc::Rc;
 
-fn wrap_keys(keys: String) -> Rc<dyn Fn() -> Result<()>> {
-    Rc::new(move || {
+/// `forward_count` applies the count the keymap was triggered with to `keys`
+fn wrap_keys(keys: String, forward_count: bool) -> KeymapFunction {
+    Rc::new(move |context| {
+        let keys = match forward_count {
+            true => context.apply_count(&keys),
+            false => keys.clone(),
+        };
         let lua = mlua::lua();
         let scroll: Function = lua.named_registry_value("cinnamon_scroll_func")?;
-        scroll.call::<_, Value>(keys.to_owned())?;
+        scroll.call::<_, Value>(keys)?;
 
         Ok(())
     })
 }
 
-fn wrap_function(func: Rc<dyn Fn() -> Result<()>>) -> Rc<dyn Fn() -> Result<()>> {
-    let func = func.clone();
-    Rc::new(move || {
+/// The scroll runs `func` later, it gets the context of the keypress
+fn wrap_function(func: KeymapFunction) -> KeymapFunction {
+    Rc::new(move |context| {
         let func = func.clone();
+        let context = context.clone();
         let lua = mlua::lua();
         let scroll: Function = lua.named_registry_value("cinnamon_scroll_func")?;
         let lua_func: Function = lua.create_function(move |_, _: ()| {
-            if let Err(e) = func() {
-                nvim::print!("Action failed: {e}");
+            if let Some(Err(e)) = panic_log::guard("cinnamon scroll action", || func(&context)) {
+                log_error!(title = "nvim-config: cinnamon", "Action failed: {e}");
             };
             Ok(())
         })?;
@@ -36,19 +42,42 @@ fn wrap_function(func: Rc<dyn Fn() -> Result<()>>) -> Rc<dyn Fn() -> Result<()>>
     })
 }
 
-pub fn wrap_action(action: NvimAction) -> Rc<dyn Fn() -> Result<()>> {
+/// cinnamon runs keys with `normal!`, which doesn't remap. `normal` runs the `<Plug>` map
+/// right away, so the scroll sees where the cursor ends up. Run through `nvim_cmd`, which
+/// takes the keys as they are, a name isn't parsed as Vimscript.
+fn plug_function(name: String) -> KeymapFunction {
+    Rc::new(move |context| {
+        let replace_termcodes: Function = lua_get_global_path("vim.api.nvim_replace_termcodes")?;
+        let nvim_cmd: Function = lua_get_global_path("vim.api.nvim_cmd")?;
+
+        let keys = context.apply_count(&format!("<Plug>{name}"));
+        let keys: mlua::String = replace_termcodes.call((keys, true, false, true))?;
+
+        let lua = mlua::lua();
+        let cmd = lua.create_table()?;
+        cmd.set("cmd", "normal")?;
+        cmd.set("args", lua.create_sequence_from([keys])?)?;
+        nvim_cmd.call::<_, Value>((cmd, lua.create_table()?))?;
+        Ok(())
+    })
+}
+
+pub fn wrap_action(action: NvimAction) -> KeymapFunction {
     match action {
-        NvimAction::Keys(k) => wrap_keys(k),
-        NvimAction::Command(c) => wrap_keys(format!(":{c}<CR>")),
+        NvimAction::Keys(k) => wrap_keys(k, true),
+        // A count before a command would become a range
+        NvimAction::Command(c) => wrap_keys(format!(":{c}<CR>"), false),
         NvimAction::Function(f) => wrap_function(f),
+        NvimAction::Plug(name) => wrap_function(plug_function(name)),
+        action => wrap_function(Rc::new(move |context| action.run(context))),
     }
 }
 
 // Probably not to be used
 #[allow(dead_code)]
 pub fn wrap_keymap(keymap: NvimKeymap) -> NvimKeymap {
-    keymap.into_iter().map(|(keys, action)| {
-        (keys, NvimAction::Function(wrap_action(action)))
+    keymap.into_iter().map(|(keys, entry)| {
+        (keys, entry.scroll(true))
     }).collect()
 }
 
@@ -68,6 +97,7 @@ pub fn setup_cinnamon() -> Result<()> {
     let scroll: Function = cinnamon.get("scroll")?;
 
     mlua::lua().set_named_registry_value("cinnamon_scroll_func", scroll)?;
+    reload::track_registry_value("cinnamon_scroll_func");
 
     Ok(())
 }
@@ -0,0 +1,300 @@
+use super::plugin::PluginError;
+use crate::{
+    Result,
+    keymap_remapping::feedkeys,
+    log_error,
+    mlua::{self, Function, Table, Value},
+    nvim::api::{
+        self, Buffer,
+        opts::{
+            CreateAugroupOpts, CreateAutocmdOpts, ExecAutocmdsOpts, GetAutocmdsOpts,
+            SetKeymapOpts,
+        },
+        types::{AutocmdCallbackArgs, Mode},
+    },
+    nvim_helper::{lua::lua_get_global_path, lua_value},
+    panic_log, reload,
+    report::{self, ReportEntry, Subsystem},
+};
+
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    rc::Rc,
+    result::Result as StdResult,
+    time::Instant,
+};
+
+#[derive(Clone, Debug)]
+pub enum LazyTrigger {
+    /// Stub user command that loads the plugin and re-runs itself
+    Command(String),
+    FileType(String),
+    Event(String),
+    /// Stub keymap that loads the plugin and feeds the keys again
+    Keys(Mode, String),
+}
+
+pub type LazyLoader = Rc<dyn Fn() -> StdResult<(), PluginError>>;
+
+#[derive(Clone)]
+struct Pending {
+    triggers: Vec<LazyTrigger>,
+    load: LazyLoader,
+}
+
+thread_local! {
+    static PENDING: RefCell<HashMap<String, Pending>> = RefCell::new(HashMap::new());
+    /// Plugins being set up, triggers fired during their setup don't recurse
+    static LOADING: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
+}
+
+fn augroup_name(plugin: &str) -> String {
+    format!("nvim_config_lazy_{plugin}")
+}
+
+/// Loads a lazy plugin if it hasn't been loaded yet, does nothing otherwise.
+/// If loading fails the stubs are installed again, the next trigger retries.
+pub fn ensure_loaded(name: &str) -> StdResult<(), PluginError> {
+    if LOADING.with_borrow(|loading| loading.contains(name)) {
+        return Ok(());
+    }
+    let Some(pending) = PENDING.with_borrow(|pending| pending.get(name).cloned()) else {
+        return Ok(());
+    };
+
+    let _context = panic_log::enter(format!("lazy plugin {name}"));
+    let start = Instant::now();
+
+    // The plugin may define a command or map of the same name as a stub
+    remove_stubs(name, &pending.triggers);
+    LOADING.with_borrow_mut(|loading| loading.insert(name.to_string()));
+    let result = (pending.load)();
+    LOADING.with_borrow_mut(|loading| loading.remove(name));
+
+    match &result {
+        Ok(()) => {
+            PENDING.with_borrow_mut(|p| p.remove(name));
+        }
+        Err(_) => {
+            _ = install_stubs(name, &pending.triggers).inspect_err(|e| {
+                log_error!("Failed to reinstall lazy triggers of {name}: {e}");
+            });
+        }
+    }
+
+    let entry = ReportEntry::new(Subsystem::Plugin, format!("{name} (lazy)")).duration(start.elapsed());
+    report::record(match &result {
+        Ok(()) => entry,
+        Err(e) => entry.failed(e),
+    });
+
+    result
+}
+
+fn autocmd_ids(event: &str) -> Result<HashSet<u32>> {
+    let opts = GetAutocmdsOpts::builder().events([event]).build();
+    Ok(api::get_autocmds(&opts)?.filter_map(|autocmd| autocmd.id).collect())
+}
+
+/// Runs `event` for `buffer` again, only in the groups of the autocmds added since `before`,
+/// so the handlers that already ran don't see it twice. Autocmds without a group are skipped.
+fn replay(event: &str, before: &HashSet<u32>, buffer: Buffer) -> Result<()> {
+    let opts = GetAutocmdsOpts::builder().events([event]).build();
+    let groups: HashSet<u32> = api::get_autocmds(&opts)?
+        .filter(|autocmd| autocmd.id.is_some_and(|id| !before.contains(&id)))
+        .filter_map(|autocmd| autocmd.group)
+        .collect();
+
+    for group in groups {
+        api::exec_autocmds(
+            [event],
+            &ExecAutocmdsOpts::builder()
+                .group(group)
+                .buffer(buffer.clone())
+                .modeline(false)
+                .build(),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Runs `cmd` the way its stub was called, `opts` is what the stub got. The arguments are
+/// passed on as typed, so quoting and escapes reach the real command's own parsing. A count
+/// before the name (`:5Cmd`) arrives as a one-line range.
+fn replay_command(cmd: &str, opts: &Table) -> Result<()> {
+    let lua = mlua::lua();
+    let command = lua.create_table()?;
+    command.set("cmd", cmd)?;
+    command.set("bang", opts.get::<_, bool>("bang")?)?;
+    command.set("mods", opts.get::<_, Table>("smods")?)?;
+
+    let args: String = opts.get("args")?;
+    let args = if args.is_empty() { vec![] } else { vec![args] };
+    command.set("args", args)?;
+
+    let line1: i64 = opts.get("line1")?;
+    let line2: i64 = opts.get("line2")?;
+    match opts.get::<_, i64>("range")? {
+        0 => {}
+        1 => command.set("range", [line2])?,
+        _ => command.set("range", [line1, line2])?,
+    }
+
+    let register: String = opts.get("reg")?;
+    if !register.is_empty() {
+        command.set("reg", register)?;
+    }
+
+    let nvim_cmd: Function = lua_get_global_path("vim.api.nvim_cmd")?;
+    nvim_cmd.call::<_, Value>((command, lua.create_table()?))?;
+
+    Ok(())
+}
+
+fn load_or_print(name: &str) -> bool {
+    match panic_log::guard(&format!("lazy trigger {name}"), || ensure_loaded(name)) {
+        Some(Ok(())) => true,
+        Some(Err(e)) => {
+            log_error!(title = "nvim-config: plugins", "Failed to lazy load {name}: {e}");
+            false
+        }
+        None => false,
+    }
+}
+
+fn install_stub(plugin: &str, trigger: &LazyTrigger, augroup: u32) -> Result<()> {
+    let plugin = plugin.to_string();
+
+    match trigger.clone() {
+        LazyTrigger::Command(cmd) => {
+            let cmd_name = cmd.clone();
+            // Created through Lua, the callback gets `smods` in the form `nvim_cmd` takes
+            let stub = mlua::lua().create_function(move |_, opts: Table| {
+                if load_or_print(&plugin) {
+                    _ = replay_command(&cmd, &opts).inspect_err(|e| {
+                        log_error!("Failed to run {cmd}: {e}");
+                    });
+                }
+                Ok(())
+            })?;
+            let create_user_command: Function = lua_get_global_path("vim.api.nvim_create_user_command")?;
+            create_user_command.call::<_, ()>((
+                cmd_name.as_str(),
+                stub,
+                lua_value!({
+                    "nargs" => "*",
+                    "bang" => true,
+                    "range" => true,
+                    "register" => true,
+                }),
+            ))?;
+            reload::track_command(&cmd_name);
+        }
+
+        LazyTrigger::FileType(ft) => {
+            api::create_autocmd(
+                ["FileType"],
+                &CreateAutocmdOpts::builder()
+                    .group(augroup)
+                    .patterns([ft.as_str()])
+                    .callback(move |args: AutocmdCallbackArgs| -> Result<bool> {
+                        // Replay so that the plugin's own FileType autocmds see this buffer
+                        let before = autocmd_ids("FileType")?;
+                        if load_or_print(&plugin) {
+                            replay("FileType", &before, args.buffer)?;
+                        }
+                        Ok(false)
+                    })
+                    .build(),
+            )?;
+        }
+
+        LazyTrigger::Event(event) => {
+            let event_name = event.clone();
+            api::create_autocmd(
+                [event_name.as_str()],
+                &CreateAutocmdOpts::builder()
+                    .group(augroup)
+                    .callback(move |args: AutocmdCallbackArgs| -> Result<bool> {
+                        let before = autocmd_ids(&event)?;
+                        if load_or_print(&plugin) {
+                            replay(&event, &before, args.buffer)?;
+                        }
+                        Ok(false)
+                    })
+                    .build(),
+            )?;
+        }
+
+        LazyTrigger::Keys(mode, lhs) => {
+            let lhs_name = lhs.clone();
+            api::set_keymap(
+                mode,
+                &lhs_name,
+                "",
+                &SetKeymapOpts::builder()
+                    .silent(true)
+                    .callback(move |()| {
+                        if load_or_print(&plugin) {
+                            _ = feedkeys(&lhs, "m").inspect_err(|e| {
+                                log_error!("Failed to replay {lhs}: {e}");
+                            });
+                        }
+                    })
+                    .build(),
+            )?;
+            reload::track_keymap(mode, &lhs_name, None);
+        }
+    }
+
+    Ok(())
+}
+
+fn remove_stubs(plugin: &str, triggers: &[LazyTrigger]) {
+    _ = api::del_augroup_by_name(&augroup_name(plugin));
+
+    for trigger in triggers {
+        match trigger {
+            LazyTrigger::Command(cmd) => {
+                _ = api::del_user_command(cmd);
+            }
+            LazyTrigger::Keys(mode, lhs) => {
+                _ = api::del_keymap(*mode, lhs);
+            }
+            LazyTrigger::FileType(_) | LazyTrigger::Event(_) => {}
+        }
+    }
+}
+
+fn install_stubs(plugin: &str, triggers: &[LazyTrigger]) -> Result<()> {
+    let augroup = api::create_augroup(
+        &augroup_name(plugin),
+        &CreateAugroupOpts::builder().clear(true).build(),
+    )?;
+
+    for trigger in triggers {
+        install_stub(plugin, trigger, augroup)?;
+    }
+
+    Ok(())
+}
+
+/// Forgets the plugins that haven't loaded, for `:ConfigReload`. Their stub commands and maps
+/// are tracked by `reload` and removed with the rest.
+pub fn clear() {
+    let pending = PENDING.take();
+    for name in pending.keys() {
+        _ = api::del_augroup_by_name(&augroup_name(name));
+    }
+    LOADING.take();
+}
+
+/// Installs the trigger stubs, `load` runs when the first one fires (or on `ensure_loaded`)
+pub fn register(name: &str, triggers: Vec<LazyTrigger>, load: LazyLoader) -> Result<()> {
+    install_stubs(name, &triggers)?;
+    PENDING.with_borrow_mut(|pending| pending.insert(name.to_string(), Pending { triggers, load }));
+
+    Ok(())
+}
@@ -1,25 +1,36 @@
 use crate::{
     Result,
-    nvim::{self, api},
+    log_warn,
+    nvim::api,
     mlua::{self, Function, Value},
     nvim_helper::{
         lua_value,
         lua_plugins::require_plugin,
     },
     keymap_remapping::KeymapFunction,
+    reload,
 };
 
 use std::rc::Rc;
 
 pub fn leap() -> KeymapFunction {
-    Rc::new(|| {
+    Rc::new(|context| {
         let func: Function = mlua::lua().named_registry_value("leap_func")?;
 
+        // As an operator target the jump has to stay in the current window
+        if context.operator_pending() {
+            let current_window = vec![api::get_current_win().handle()];
+            _ = func.call::<_, Value>(lua_value!({
+                "target_windows" => current_window,
+            }));
+            return Ok(());
+        }
+
         let focusable_windows = api::list_wins()
             .filter_map(|w| {
                 match w.get_config() {
                     Err(e) => {
-                        nvim::print!("Error: {e}");
+                        log_warn!("Failed to get window config: {e}");
                         None
                     },
                     Ok(c) => if c.focusable.unwrap_or(false) {
@@ -41,5 +52,6 @@ pub fn leap() -> KeymapFunction {
 pub fn setup_leap() -> Result<()> {
     let leap_func: Function = require_plugin("leap")?.get("leap")?;
     mlua::lua().set_named_registry_value("leap_func", leap_func)?;
+    reload::track_registry_value("leap_func");
     Ok(())
 }
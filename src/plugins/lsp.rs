@@ -1,19 +1,28 @@
 use crate::{
-    keymap_remapping::setup_buf_keymap,
+    keymap_remapping::{keymaps_by_mode, remove_buf_keymap, setup_buf_keymap, KeymapFunction, NvimKeymap},
+    log_error,
     mlua::{self, prelude::LuaResult, Function, Table, Value},
-    nvim::{
-        self,
-        api::{types::Mode, Buffer},
+    nvim::api::{
+        self, Buffer,
+        opts::{CreateAugroupOpts, CreateAutocmdOpts},
+        types::AutocmdCallbackArgs,
     },
     nvim_helper::{lua::lua_get_global_path, lua_plugins::require_plugin, lua_value},
-    nvim_keymap, Result,
+    nvim_keymap, panic_log, reload,
+    report::{self, Subsystem},
+    Result,
 };
 
-use std::rc::Rc;
+use std::{cell::Cell, rc::Rc};
 
-pub fn lua_registry_named_function(name: &str) -> Rc<dyn Fn() -> Result<()>> {
+thread_local! {
+    /// Off after `:KeymapRestore`, `setup_lsp` turns it on again
+    static ATTACH_KEYMAPS: Cell<bool> = const { Cell::new(true) };
+}
+
+pub fn lua_registry_named_function(name: &str) -> KeymapFunction {
     let name = name.to_string();
-    Rc::new(move || {
+    Rc::new(move |_| {
         let func: Function = mlua::lua().named_registry_value(&name)?;
         _ = func.call::<_, Value>(());
 
@@ -24,6 +33,7 @@ pub fn lua_registry_named_function(name: &str) -> Rc<dyn Fn() -> Result<()>> {
 fn lsp_setup_func(name: &str, path: &str) -> Result<()> {
     let func: Function = lua_get_global_path(path)?;
     mlua::lua().set_named_registry_value(name, func)?;
+    reload::track_registry_value(name);
 
     Ok(())
 }
@@ -49,34 +59,118 @@ fn lsp_define_commands() -> Result<()> {
     Ok(())
 }
 
-fn lsp_setup_keymap() -> Result<()> {
-    let insert_keymap = nvim_keymap!(
-        ("<C-k>" => ! lua_registry_named_function("lsp_hover")),
-        ("<C-l>" => ! lua_registry_named_function("lsp_signature_help")),
-    );
-    let normal_keymap = nvim_keymap!(
-        (".d" => @ ! lua_registry_named_function("lsp_goto_definition")),
-        (".D" => @ ! lua_registry_named_function("lsp_goto_declaration")),
-        (".i" => @ ! lua_registry_named_function("lsp_goto_implementation")),
-        (".t" => @ ! lua_registry_named_function("lsp_goto_type_definition")),
-        (".r" => "TelescopeCall lsp_references"),
+/// Whether `TelescopeCall` is defined, it isn't when telescope (or plenary) is missing
+fn telescope_available() -> bool {
+    let exists = || -> Result<i64> {
+        let exists: Function = lua_get_global_path("vim.fn.exists")?;
+        Ok(exists.call(":TelescopeCall")?)
+    };
+    exists().is_ok_and(|found| found == 2)
+}
 
-        (".q" => "TelescopeCall diagnostics"),
-        (".," => ! lua_registry_named_function("lsp_peek_diagnostic")),
+/// Maps listing things in telescope, only set when `TelescopeCall` exists
+fn lsp_telescope_keymaps() -> NvimKeymap {
+    nvim_keymap!(
+        { "Go to":
+            (desc "References" ".r" => "TelescopeCall lsp_references"),
+        },
 
-        (".k" => "TelescopeCall lsp_document_symbols"),
-        (".K" => "TelescopeCall lsp_workspace_symbols"),
+        { "Diagnostics":
+            (desc "List diagnostics" ".q" => "TelescopeCall diagnostics"),
+        },
 
-        (".a" => ! lua_registry_named_function("lsp_code_action")),
-        (".R" => ! lua_registry_named_function("lsp_rename")),
-        (".f" => ! lua_registry_named_function("lsp_format")),
+        { "Symbols":
+            (desc "Document symbols" ".k" => "TelescopeCall lsp_document_symbols"),
+            (desc "Workspace symbols" ".K" => "TelescopeCall lsp_workspace_symbols"),
+        },
+    )
+}
 
-        ("<C-k>" => ! lua_registry_named_function("lsp_hover")),
-        ("<C-l>" => ! lua_registry_named_function("lsp_signature_help")),
+/// Buffer-local keymaps set on attach
+pub fn lsp_keymaps() -> NvimKeymap {
+    let mut keymap = nvim_keymap!(
+        { "Go to":
+            (desc "Definition" ".d" => @ ! lua_registry_named_function("lsp_goto_definition")),
+            (desc "Declaration" ".D" => @ ! lua_registry_named_function("lsp_goto_declaration")),
+            (desc "Implementation" ".i" => @ ! lua_registry_named_function("lsp_goto_implementation")),
+            (desc "Type definition" ".t" => @ ! lua_registry_named_function("lsp_goto_type_definition")),
+        },
+
+        { "Diagnostics":
+            (desc "Peek diagnostic" ".," => ! lua_registry_named_function("lsp_peek_diagnostic")),
+        },
+
+        { "Code":
+            (desc "Code action" ".a" => ! lua_registry_named_function("lsp_code_action")),
+            (desc "Rename" ".R" => ! lua_registry_named_function("lsp_rename")),
+            (desc "Format" ".f" => ! lua_registry_named_function("lsp_format")),
+        },
+
+        ([n, v, i] desc "Hover" "<C-k>" => ! lua_registry_named_function("lsp_hover")),
+        ([n, v, i] desc "Signature help" "<C-l>" => ! lua_registry_named_function("lsp_signature_help")),
     );
-    setup_buf_keymap(&mut Buffer::current(), Mode::Visual, normal_keymap.clone())?;
-    setup_buf_keymap(&mut Buffer::current(), Mode::Normal, normal_keymap)?;
-    setup_buf_keymap(&mut Buffer::current(), Mode::Insert, insert_keymap)?;
+
+    if telescope_available() {
+        keymap.merge(lsp_telescope_keymaps());
+    }
+    keymap
+}
+
+/// Owner of the LSP keymaps in the keymap registry
+const KEYMAP_OWNER: &str = "lsp";
+
+/// Buffers attached from now on don't get the LSP keymaps, until the next `setup_lsp`
+pub fn disable_keymaps() {
+    ATTACH_KEYMAPS.set(false);
+}
+
+fn lsp_setup_keymap() -> Result<()> {
+    if !ATTACH_KEYMAPS.get() {
+        return Ok(());
+    }
+
+    for (mode, keymap) in keymaps_by_mode(&lsp_keymaps()) {
+        setup_buf_keymap(&mut Buffer::current(), mode, KEYMAP_OWNER, keymap)?;
+    }
+    Ok(())
+}
+
+/// Removes the LSP keymaps of `buf`, once the last client detaches
+fn lsp_remove_keymap(buf: &mut Buffer) -> Result<()> {
+    // The detaching client is still listed
+    let get_clients: Function = lua_get_global_path("vim.lsp.get_clients")?;
+    let clients: Table = get_clients.call(lua_value!({ "bufnr" => buf.handle() }))?;
+    if clients.raw_len() > 1 {
+        return Ok(());
+    }
+
+    for (mode, _) in keymaps_by_mode(&lsp_keymaps()) {
+        remove_buf_keymap(buf, mode, KEYMAP_OWNER)?;
+    }
+    Ok(())
+}
+
+fn lsp_setup_detach() -> Result<()> {
+    let augroup = api::create_augroup(
+        "nvim_config_lsp",
+        &CreateAugroupOpts::builder().clear(true).build(),
+    )?;
+
+    api::create_autocmd(
+        ["LspDetach"],
+        &CreateAutocmdOpts::builder()
+            .group(augroup)
+            .callback(|mut args: AutocmdCallbackArgs| -> Result<bool> {
+                panic_log::guard("lsp detach", || {
+                    _ = lsp_remove_keymap(&mut args.buffer).inspect_err(|e| {
+                        log_error!("Error while removing lsp keymap: {e}");
+                    });
+                });
+                Ok(false)
+            })
+            .build(),
+    )?;
+
     Ok(())
 }
 
@@ -108,9 +202,7 @@ fn setup_lang_with_settings(
     on_attach: &Function,
     settings: Option<&Value>,
 ) -> Result<()> {
-    let config: Table = lspconfig.get(lang).inspect_err(|_| {
-        nvim::print!("Missing lspconfig lang: {lang}");
-    })?;
+    let config: Table = lspconfig.get(lang)?;
     let setup: Function = config.get("setup")?;
 
     setup.call::<_, Value>(lua_value!({
@@ -136,48 +228,41 @@ fn setup_lang(
 pub fn setup_lsp() -> Result<()> {
     let lspconfig: Table = require_plugin("lspconfig")?;
     lsp_define_commands()?;
+    lsp_setup_detach()?;
+    ATTACH_KEYMAPS.set(true);
 
     let on_attach = mlua::lua().create_function(|_: &mlua::Lua, _: ()| -> LuaResult<()> {
-        _ = lsp_setup_keymap().inspect_err(|e| {
-            nvim::print!("Error while setting up lsp keymap: {e}");
+        panic_log::guard("lsp on_attach", || {
+            _ = lsp_setup_keymap().inspect_err(|e| {
+                log_error!("Error while setting up lsp keymap: {e}");
+            });
         });
         Ok(())
     })?;
 
     let lsp_capabilities = blink_cmp_capabilities()?;
 
-    setup_lang_with_settings(
-        "rust_analyzer",
-        &lsp_capabilities,
-        &lspconfig,
-        &on_attach,
-        Some(&lua_value!({
-            "rust-analyzer" => {
-                "check" => {
-                    "command" => "clippy",
+    _ = report::timed(Subsystem::Lsp, "rust_analyzer", || {
+        setup_lang_with_settings(
+            "rust_analyzer",
+            &lsp_capabilities,
+            &lspconfig,
+            &on_attach,
+            Some(&lua_value!({
+                "rust-analyzer" => {
+                    "check" => {
+                        "command" => "clippy",
+                    },
                 },
-            },
-        })),
-    )
-    .inspect_err(|_| {
-        nvim::print!("Failed to set up rust_analyzer lsp");
-    })?;
-
-    setup_lang("clangd", &lsp_capabilities, &lspconfig, &on_attach).inspect_err(|_| {
-        nvim::print!("Failed to set up clangd lsp");
-    })?;
-
-    setup_lang("lua_ls", &lsp_capabilities, &lspconfig, &on_attach).inspect_err(|_| {
-        nvim::print!("Failed to set up lua_ls lsp");
-    })?;
-
-    setup_lang("ruff", &lsp_capabilities, &lspconfig, &on_attach).inspect_err(|_| {
-        nvim::print!("Failed to set up ruff");
-    })?;
+            })),
+        )
+    });
 
-    setup_lang("basedpyright", &lsp_capabilities, &lspconfig, &on_attach).inspect_err(|_| {
-        nvim::print!("Failed to set up basedpyright");
-    })?;
+    for lang in ["clangd", "lua_ls", "ruff", "basedpyright"] {
+        _ = report::timed(Subsystem::Lsp, lang, || {
+            setup_lang(lang, &lsp_capabilities, &lspconfig, &on_attach)
+        });
+    }
 
     Ok(())
 }
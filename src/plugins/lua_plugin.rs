@@ -1,7 +1,11 @@
-use super::plugin::{Plugin, PluginError};
+use super::{
+    lazy::{self, LazyTrigger},
+    plugin::{Plugin, PluginError},
+};
 use crate::{
     Result,
     mlua::{self, FromLuaMulti, Function, IntoLuaMulti},
+    nvim::api::types::Mode,
     nvim_helper::{lua_plugins::require_plugin, lua_value},
 };
 use std::{rc::Rc, result::Result as StdResult};
@@ -14,14 +18,38 @@ where
     R: FromLuaMulti<'lua>,
 {
     name: String,
+    dependencies: Vec<String>,
+    modules: Vec<String>,
 
     setup_func: String,
     pre_setup: Option<Rc<dyn Fn() -> Result<A>>>,
     post_setup: Option<Rc<dyn Fn(R) -> Result<()>>>,
 
+    /// `None` means the plugin is set up eagerly
+    lazy: Option<Vec<LazyTrigger>>,
+
     _marker: PhantomData<&'lua ()>,
 }
 
+impl<'lua, A, R> Clone for LuaPlugin<'lua, A, R>
+where
+    A: IntoLuaMulti<'lua>,
+    R: FromLuaMulti<'lua>,
+{
+    fn clone(&self) -> Self {
+        LuaPlugin {
+            name: self.name.clone(),
+            dependencies: self.dependencies.clone(),
+            modules: self.modules.clone(),
+            setup_func: self.setup_func.clone(),
+            pre_setup: self.pre_setup.clone(),
+            post_setup: self.post_setup.clone(),
+            lazy: self.lazy.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
 pub struct LuaPluginBuilder<'lua, A, R>
 where
     A: IntoLuaMulti<'lua>,
@@ -36,10 +64,50 @@ where
     R: FromLuaMulti<'lua>,
 {
     pub fn setup_func(mut self, name: impl Into<String>) -> Self {
-        self.plugin.name = name.into();
+        self.plugin.setup_func = name.into();
+        self
+    }
+
+    pub fn depends_on(mut self, name: impl Into<String>) -> Self {
+        self.plugin.dependencies.push(name.into());
         self
     }
 
+    pub fn requires_module(mut self, name: impl Into<String>) -> Self {
+        self.plugin.modules.push(name.into());
+        self
+    }
+
+    /// Only set up on `lazy::ensure_loaded`
+    pub fn lazy(mut self) -> Self {
+        self.plugin.lazy.get_or_insert_with(Vec::new);
+        self
+    }
+
+    fn lazy_on(mut self, trigger: LazyTrigger) -> Self {
+        self.plugin.lazy.get_or_insert_with(Vec::new).push(trigger);
+        self
+    }
+
+    pub fn lazy_on_command(self, cmd: impl Into<String>) -> Self {
+        self.lazy_on(LazyTrigger::Command(cmd.into()))
+    }
+
+    #[allow(dead_code)]
+    pub fn lazy_on_filetype(self, ft: impl Into<String>) -> Self {
+        self.lazy_on(LazyTrigger::FileType(ft.into()))
+    }
+
+    #[allow(dead_code)]
+    pub fn lazy_on_event(self, event: impl Into<String>) -> Self {
+        self.lazy_on(LazyTrigger::Event(event.into()))
+    }
+
+    #[allow(dead_code)]
+    pub fn lazy_on_keys(self, mode: Mode, keys: impl Into<String>) -> Self {
+        self.lazy_on(LazyTrigger::Keys(mode, keys.into()))
+    }
+
     pub fn pre_setup<F>(mut self, f: F) -> Self
     where
         F: Fn() -> Result<A> + 'static,
@@ -94,9 +162,12 @@ where
         LuaPluginBuilder {
             plugin: LuaPlugin {
                 name: name.into(),
+                dependencies: Vec::new(),
+                modules: Vec::new(),
                 setup_func: "setup".into(),
                 pre_setup: None,
                 post_setup: None,
+                lazy: None,
                 _marker: PhantomData,
             },
         }
@@ -128,14 +199,8 @@ where
 
         Ok(())
     }
-}
 
-impl<'lua, A, R> Plugin for LuaPlugin<'lua, A, R>
-where
-    A: IntoLuaMulti<'lua>,
-    R: FromLuaMulti<'lua>,
-{
-    fn setup(&self) -> StdResult<(), PluginError> {
+    fn setup_now(&self) -> StdResult<(), PluginError> {
         match self.lua_plugin_setup() {
             Ok(o) => Ok(o),
             Err(LuaPluginSetupError::Plugin(e)) => Err(e),
@@ -145,6 +210,34 @@ where
     }
 }
 
+impl<A, R> Plugin for LuaPlugin<'static, A, R>
+where
+    A: IntoLuaMulti<'static> + 'static,
+    R: FromLuaMulti<'static> + 'static,
+{
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn dependencies(&self) -> &[String] {
+        &self.dependencies
+    }
+
+    fn required_modules(&self) -> &[String] {
+        &self.modules
+    }
+
+    fn setup(&self) -> StdResult<(), PluginError> {
+        let Some(triggers) = self.lazy.clone() else {
+            return self.setup_now();
+        };
+
+        let plugin = self.clone();
+        lazy::register(&self.name, triggers, Rc::new(move || plugin.setup_now()))
+            .map_err(|e| PluginError::Other(Box::new(e)))
+    }
+}
+
 #[macro_export]
 macro_rules! lua_plugin {
     ($name:expr) => {
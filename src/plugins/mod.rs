@@ -1,22 +1,34 @@
 pub mod cinnamon;
+pub mod lazy;
 pub mod leap;
 pub mod lsp;
 mod lua_plugin;
 mod plugin;
+mod rust_plugin;
 pub mod spectre;
 pub mod telescope;
 
 use nvim_api_helper::{lua::lua_get_global_path, mlua};
 
+use std::{collections::HashSet, time::Instant};
+
 use crate::{
-    Result, lua_plugin,
+    Result,
+    config::{Config, OptionValue, PluginConfig, overlay, toml_to_lua},
+    log_error, log_warn, lua_plugin,
     nvim::{
         self,
         api::{self, opts::OptionOpts},
     },
     nvim_dir,
     nvim_helper::lua_value,
-    plugins::{lua_plugin::LuaPlugin, plugin::Plugin},
+    panic_log, reload,
+    report::{self, ReportEntry, Subsystem},
+    plugins::{
+        lua_plugin::LuaPlugin,
+        plugin::{Plugin, PluginError},
+        rust_plugin::RustPlugin,
+    },
 };
 
 fn setup_colorscheme() -> Result<()> {
@@ -24,13 +36,35 @@ fn setup_colorscheme() -> Result<()> {
     Ok(())
 }
 
-fn setup_native_settings() -> Result<()> {
-    nvim::api::set_option("number", true)?;
-    nvim::api::set_option("scrolloff", 10)?;
-    nvim::api::set_option("tabstop", 4)?;
-    nvim::api::set_option("shiftwidth", 4)?;
-    nvim::api::set_option("softtabstop", 4)?;
-    nvim::api::set_option("expandtab", true)?;
+fn default_options() -> Vec<(String, OptionValue)> {
+    vec![
+        ("number".into(), OptionValue::Bool(true)),
+        ("scrolloff".into(), OptionValue::Int(10)),
+        ("tabstop".into(), OptionValue::Int(4)),
+        ("shiftwidth".into(), OptionValue::Int(4)),
+        ("softtabstop".into(), OptionValue::Int(4)),
+        ("expandtab".into(), OptionValue::Bool(true)),
+    ]
+}
+
+fn set_option(name: &str, value: &OptionValue) -> Result<()> {
+    reload::track_option(name);
+    match value {
+        OptionValue::Bool(b) => nvim::api::set_option(name, *b)?,
+        OptionValue::Int(i) => nvim::api::set_option(name, *i)?,
+        OptionValue::Str(s) => nvim::api::set_option(name, s.as_str())?,
+    }
+    Ok(())
+}
+
+fn setup_native_settings(config: &Config) -> Result<()> {
+    let configured = config.options.iter().flatten().map(|(n, v)| (n.clone(), v.clone()));
+    let options = overlay(default_options(), configured, |(name, _)| name);
+    for (name, value) in &options {
+        if let Err(e) = set_option(name, value) {
+            report::record(ReportEntry::new(Subsystem::Settings, format!("option {name}")).failed(&e));
+        }
+    }
 
     // Firenvim
     mlua::lua().globals().set(
@@ -47,11 +81,13 @@ fn setup_native_settings() -> Result<()> {
         }),
     )?;
     if lua_get_global_path::<bool>("started_by_firenvim")? {
+        reload::track_option("laststatus");
         nvim::api::set_option("laststatus", 0)?;
     }
 
     const ENABLE_NEOVIDE: bool = true;
     if ENABLE_NEOVIDE {
+        reload::track_option("guifont");
         nvim::api::set_option("guifont", "Hack Nerd Font:h9")?;
         nvim::api::command("let g:neovide_cursor_animation_length = 0.05")?;
         nvim::api::command("let g:neovide_cursor_trail_size = 0.3")?;
@@ -62,8 +98,8 @@ fn setup_native_settings() -> Result<()> {
     Ok(())
 }
 
-pub fn setup_plugins() {
-    let plugins: Vec<Box<dyn Plugin>> = vec![
+fn default_plugins() -> Vec<Box<dyn Plugin>> {
+    vec![
         lua_plugin!("nvim-autopairs"),
         lua_plugin!("term-edit", {
             "prompt_end" => "❯ ",
@@ -75,6 +111,63 @@ pub fn setup_plugins() {
         }),
         lua_plugin!("guess-indent"),
         lua_plugin!("dirbuf"),
+    ]
+}
+
+fn config_plugin(config: &PluginConfig) -> Box<dyn Plugin> {
+    let setup = config.setup.clone();
+    let builder = LuaPlugin::<_, ()>::builder(&config.name).pre_setup(move || {
+        let lua = mlua::lua();
+        match &setup {
+            Some(setup) => Ok(toml_to_lua(lua, setup)?),
+            None => Ok(mlua::Value::Table(lua.create_table()?)),
+        }
+    });
+    let builder = match &config.setup_func {
+        Some(func) => builder.setup_func(func),
+        None => builder,
+    };
+    let builder = config
+        .depends_on
+        .iter()
+        .fold(builder, |builder, dep| builder.depends_on(dep));
+    let builder = config
+        .requires_module
+        .iter()
+        .fold(builder, |builder, module| builder.requires_module(module));
+    Box::new(builder.build())
+}
+
+/// Whether the Lua module `name` is on the runtimepath, without loading it. `required_modules`
+/// of the plugins (plenary, lspconfig etc.) are checked with this.
+fn lua_module_installed(name: &str) -> bool {
+    let path = name.replace('.', "/");
+    [format!("lua/{path}.lua"), format!("lua/{path}/init.lua")]
+        .into_iter()
+        .any(|file| api::get_runtime_file(file, false).is_ok_and(|mut files| files.next().is_some()))
+}
+
+fn notify_plugin_error(name: &str, error: &PluginError) {
+    const TITLE: &str = "nvim-config: plugins";
+
+    match error {
+        PluginError::NotInstalled(_) => {
+            log_warn!(title = TITLE, "Plugin {name} doesn't seem to be installed")
+        }
+        PluginError::DependencyMissing(dep) => {
+            log_warn!(title = TITLE, "Dependency {dep} of {name} seems to be missing")
+        }
+        PluginError::DependencyCycle(_) => log_error!(title = TITLE, "{error}"),
+        PluginError::Other(e) => log_error!(title = TITLE, "Failed to set up {name}: {e}"),
+    }
+}
+
+pub fn setup_plugins(config: &Config) {
+    let configured = config.plugins.iter().flatten();
+    let mut plugins = overlay(default_plugins(), configured.clone().map(config_plugin), |p| p.name());
+    plugins.retain(|p| !configured.clone().any(|c| !c.enabled && c.name == p.name()));
+
+    plugins.push(
         Box::new(
             LuaPlugin::<_, ()>::builder("nvim-treesitter.configs")
                 .pre_setup(|| {
@@ -85,6 +178,7 @@ pub fn setup_plugins() {
                         api::get_option_value("runtimepath", &OptionOpts::builder().build())?;
                     let modified_rtp = format!("{tree_sitter_dir},{current_rtp}");
 
+                    reload::track_option("runtimepath");
                     api::set_option_value(
                         "runtimepath",
                         modified_rtp,
@@ -106,44 +200,67 @@ pub fn setup_plugins() {
                 })
                 .build(),
         ),
-    ];
+    );
 
-    for plugin in plugins {
-        match plugin.setup() {
-            Ok(()) => {}
-            Err(plugin::PluginError::NotInstalled(name)) => {
-                nvim::print!("Plugin {name} doesn't seem to be installed")
-            }
-            Err(plugin::PluginError::DependencyMissing(name)) => {
-                nvim::print!("Dependency {name} seems to be missing")
-            }
-            Err(plugin::PluginError::Other(e)) => {
-                nvim::print!("Error occured: {e}")
-            }
-        }
-    }
+    // leap and cinnamon don't need other plugins, they only have to be set up before the keymaps
+    plugins.extend([
+        Box::new(RustPlugin::new("leap", leap::setup_leap)) as Box<dyn Plugin>,
+        telescope::telescope_plugin(),
+        spectre::spectre_plugin(),
+        Box::new(
+            RustPlugin::new("lsp", lsp::setup_lsp)
+                .requires_module("lspconfig")
+                .requires_module("blink.cmp"),
+        ),
+        Box::new(RustPlugin::new("cinnamon", cinnamon::setup_cinnamon)),
+    ]);
 
-    if let Err(e) = setup_native_settings() {
-        nvim::print!("Failed to initialize native settings: {e}");
-    };
+    // Native settings go first, some plugins read globals set there on setup
+    _ = report::timed(Subsystem::Settings, "native settings", || setup_native_settings(config));
 
-    if let Err(e) = setup_colorscheme() {
-        nvim::print!("Failed to initialize colorscheme: {e}");
-    };
+    let (order, unresolved) = plugin::resolve_order(&plugins);
+    let mut initialized = HashSet::new();
 
-    if let Err(e) = leap::setup_leap() {
-        nvim::print!("Failed to initialize leap: {e}");
-    };
+    for i in order {
+        let plugin = &plugins[i];
 
-    if let Err(e) = telescope::setup_telescope() {
-        nvim::print!("Failed to setup telescope: {e}");
-    }
+        let missing = plugin
+            .dependencies()
+            .iter()
+            .find(|dep| !initialized.contains(dep.as_str()))
+            .or_else(|| {
+                plugin.required_modules().iter().find(|module| !lua_module_installed(module))
+            });
+
+        let _context = panic_log::enter(format!("plugin {}", plugin.name()));
+        let start = Instant::now();
+        let result = match missing {
+            Some(dep) => Err(PluginError::DependencyMissing(dep.clone())),
+            None => plugin.setup(),
+        };
+        let entry = ReportEntry::new(Subsystem::Plugin, plugin.name()).duration(start.elapsed());
 
-    if let Err(e) = lsp::setup_lsp() {
-        nvim::print!("Failed to setup lsp: {e}");
+        if let Err(e) = &result {
+            notify_plugin_error(plugin.name(), e);
+        }
+
+        report::record(match result {
+            Ok(()) => {
+                initialized.insert(plugin.name());
+                entry
+            }
+            Err(e @ PluginError::DependencyMissing(_)) => entry.skipped(&e),
+            Err(e) => entry.failed(&e),
+        });
     }
 
-    if let Err(e) = cinnamon::setup_cinnamon() {
-        nvim::print!("Failed to setup cinnamon: {e}");
+    for cycle in plugin::find_cycles(&plugins, &unresolved) {
+        notify_plugin_error(&cycle[0], &PluginError::DependencyCycle(cycle.clone()));
     }
+    for &i in &unresolved {
+        let cycle = PluginError::DependencyCycle(plugin::find_cycle(&plugins, &unresolved, i));
+        report::record(ReportEntry::new(Subsystem::Plugin, plugins[i].name()).skipped(&cycle));
+    }
+
+    _ = report::timed(Subsystem::Settings, "colorscheme", setup_colorscheme);
 }
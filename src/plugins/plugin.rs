@@ -5,6 +5,9 @@ pub enum PluginError {
     #[error("Depency missing: {0}")]
     DependencyMissing(String),
 
+    #[error("Dependency cycle: {}", .0.join(" -> "))]
+    DependencyCycle(Vec<String>),
+
     #[error("Plugin not installed: {0}")]
     NotInstalled(String),
 
@@ -13,5 +16,205 @@ pub enum PluginError {
 }
 
 pub trait Plugin {
+    fn name(&self) -> &str;
+
+    /// Names of plugins set up before this one
+    fn dependencies(&self) -> &[String] {
+        &[]
+    }
+
+    /// Lua modules that have to be installed, they aren't plugins of the config (plenary etc.)
+    fn required_modules(&self) -> &[String] {
+        &[]
+    }
+
     fn setup(&self) -> Result<(), PluginError>;
 }
+
+/// Returns plugin indices ordered so that every plugin comes after its dependencies,
+/// keeping the original order where possible. Plugins that are part of a cycle
+/// (or depend on one) are left out and returned as the second element.
+/// Dependencies that aren't in `plugins` at all are ignored here, they are reported
+/// as missing when the plugin is set up.
+pub fn resolve_order(plugins: &[Box<dyn Plugin>]) -> (Vec<usize>, Vec<usize>) {
+    let index_of = |name: &str| plugins.iter().position(|p| p.name() == name);
+
+    let mut ordered = Vec::with_capacity(plugins.len());
+    let mut done = vec![false; plugins.len()];
+
+    loop {
+        let next = (0..plugins.len()).find(|&i| {
+            !done[i]
+                && plugins[i]
+                    .dependencies()
+                    .iter()
+                    .filter_map(|dep| index_of(dep))
+                    .all(|dep| done[dep])
+        });
+
+        match next {
+            Some(i) => {
+                done[i] = true;
+                ordered.push(i);
+            }
+            None => break,
+        }
+    }
+
+    let unresolved = (0..plugins.len()).filter(|&i| !done[i]).collect();
+
+    (ordered, unresolved)
+}
+
+/// Follows unresolved dependencies from `start` until a plugin repeats, returning the
+/// indices on the cycle, the repeated plugin at both ends.
+fn cycle_from(plugins: &[Box<dyn Plugin>], unresolved: &[usize], start: usize) -> Vec<usize> {
+    let index_of = |name: &str| plugins.iter().position(|p| p.name() == name);
+
+    let mut path = vec![start];
+
+    loop {
+        let current = path[path.len() - 1];
+
+        // Every unresolved plugin has at least one unresolved dependency
+        let Some(next) = plugins[current]
+            .dependencies()
+            .iter()
+            .filter_map(|dep| index_of(dep))
+            .find(|dep| unresolved.contains(dep))
+        else {
+            return Vec::new();
+        };
+
+        if let Some(pos) = path.iter().position(|&p| p == next) {
+            let mut cycle = path.split_off(pos);
+            cycle.push(next);
+            return cycle;
+        }
+
+        path.push(next);
+    }
+}
+
+fn names(plugins: &[Box<dyn Plugin>], indices: &[usize]) -> Vec<String> {
+    indices
+        .iter()
+        .map(|&i| plugins[i].name().to_string())
+        .collect()
+}
+
+/// The cycle the unresolved plugin `start` is part of or depends on, as a path of names
+pub fn find_cycle(plugins: &[Box<dyn Plugin>], unresolved: &[usize], start: usize) -> Vec<String> {
+    names(plugins, &cycle_from(plugins, unresolved, start))
+}
+
+/// Every distinct cycle among the unresolved plugins
+pub fn find_cycles(plugins: &[Box<dyn Plugin>], unresolved: &[usize]) -> Vec<Vec<String>> {
+    let mut in_cycle = vec![false; plugins.len()];
+    let mut cycles = Vec::new();
+
+    for &start in unresolved {
+        let cycle = cycle_from(plugins, unresolved, start);
+        // Plugins depending on a cycle lead into one that was already found
+        if cycle.is_empty() || in_cycle[cycle[0]] {
+            continue;
+        }
+
+        for &i in &cycle {
+            in_cycle[i] = true;
+        }
+        cycles.push(names(plugins, &cycle));
+    }
+
+    cycles
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestPlugin {
+        name: String,
+        dependencies: Vec<String>,
+    }
+
+    impl Plugin for TestPlugin {
+        fn name(&self) -> &str {
+            &self.name
+        }
+
+        fn dependencies(&self) -> &[String] {
+            &self.dependencies
+        }
+
+        fn setup(&self) -> Result<(), PluginError> {
+            Ok(())
+        }
+    }
+
+    /// Name and its dependencies separated by spaces
+    fn plugins(specs: &[(&str, &str)]) -> Vec<Box<dyn Plugin>> {
+        specs
+            .iter()
+            .map(|(name, dependencies)| {
+                Box::new(TestPlugin {
+                    name: name.to_string(),
+                    dependencies: dependencies.split_whitespace().map(String::from).collect(),
+                }) as Box<dyn Plugin>
+            })
+            .collect()
+    }
+
+    #[test]
+    fn keeps_order_without_dependencies() {
+        let plugins = plugins(&[("a", ""), ("b", ""), ("c", "")]);
+        assert_eq!(resolve_order(&plugins), (vec![0, 1, 2], vec![]));
+    }
+
+    #[test]
+    fn orders_after_dependencies() {
+        let plugins = plugins(&[("a", "c"), ("b", ""), ("c", "b")]);
+        assert_eq!(resolve_order(&plugins), (vec![1, 2, 0], vec![]));
+    }
+
+    #[test]
+    fn ignores_missing_dependencies() {
+        let plugins = plugins(&[("a", "plenary"), ("b", "a")]);
+        assert_eq!(resolve_order(&plugins), (vec![0, 1], vec![]));
+    }
+
+    #[test]
+    fn leaves_out_cycles_and_their_dependents() {
+        let plugins = plugins(&[("a", "b"), ("b", "a"), ("c", "a"), ("d", "")]);
+        assert_eq!(resolve_order(&plugins), (vec![3], vec![0, 1, 2]));
+    }
+
+    #[test]
+    fn finds_cycle_from_dependent() {
+        let plugins = plugins(&[("a", "b"), ("b", "c"), ("c", "b")]);
+        let (_, unresolved) = resolve_order(&plugins);
+        assert_eq!(find_cycle(&plugins, &unresolved, 0), ["b", "c", "b"]);
+    }
+
+    #[test]
+    fn finds_every_cycle_once() {
+        let plugins = plugins(&[
+            ("a", "b"),
+            ("b", "a"),
+            ("c", "a"),
+            ("d", "e"),
+            ("e", "f"),
+            ("f", "d"),
+            ("g", "g"),
+        ]);
+        let (_, unresolved) = resolve_order(&plugins);
+        assert_eq!(
+            find_cycles(&plugins, &unresolved),
+            [
+                vec!["a", "b", "a"],
+                vec!["d", "e", "f", "d"],
+                vec!["g", "g"]
+            ]
+        );
+    }
+}
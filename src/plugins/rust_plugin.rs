@@ -0,0 +1,51 @@
+use super::plugin::{Plugin, PluginError};
+use crate::Result;
+
+use std::result::Result as StdResult;
+
+/// Plugin whose setup is implemented in Rust (registry values, user commands etc.)
+pub struct RustPlugin {
+    name: String,
+    dependencies: Vec<String>,
+    modules: Vec<String>,
+    setup_func: fn() -> Result<()>,
+}
+
+impl RustPlugin {
+    pub fn new(name: impl Into<String>, setup_func: fn() -> Result<()>) -> Self {
+        RustPlugin {
+            name: name.into(),
+            dependencies: Vec::new(),
+            modules: Vec::new(),
+            setup_func,
+        }
+    }
+
+    pub fn depends_on(mut self, name: impl Into<String>) -> Self {
+        self.dependencies.push(name.into());
+        self
+    }
+
+    pub fn requires_module(mut self, name: impl Into<String>) -> Self {
+        self.modules.push(name.into());
+        self
+    }
+}
+
+impl Plugin for RustPlugin {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn dependencies(&self) -> &[String] {
+        &self.dependencies
+    }
+
+    fn required_modules(&self) -> &[String] {
+        &self.modules
+    }
+
+    fn setup(&self) -> StdResult<(), PluginError> {
+        (self.setup_func)().map_err(|e| PluginError::Other(Box::new(e)))
+    }
+}
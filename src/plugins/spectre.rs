@@ -1,23 +1,31 @@
+use super::{lazy, lua_plugin::LuaPlugin, plugin::Plugin};
 use crate::{
     Result,
+    keymap_remapping::KeymapFunction,
     mlua::{self, Table, Function, Value},
     nvim_helper::{lua_value, lua_plugins::require_plugin},
+    reload,
 };
 
 use std::rc::Rc;
 
-pub fn spectre_toggle() -> Rc<dyn Fn() -> Result<()>> {
-    Rc::new(|| {
-        let func: Function = mlua::lua().named_registry_value("spectre_toggle_func")?;
+fn spectre_func(name: &str) -> Result<Function<'static>> {
+    lazy::ensure_loaded("spectre").map_err(|e| mlua::Error::RuntimeError(e.to_string()))?;
+    Ok(mlua::lua().named_registry_value(name)?)
+}
+
+pub fn spectre_toggle() -> KeymapFunction {
+    Rc::new(|_| {
+        let func = spectre_func("spectre_toggle_func")?;
         _ = func.call::<_, Value>(());
         Ok(())
     })
 }
 
 #[allow(dead_code)]
-pub fn spectre_open_visual(select_word: bool) -> Rc<dyn Fn() -> Result<()>> {
-    Rc::new(move || {
-        let func: Function = mlua::lua().named_registry_value("spectre_open_visual_func")?;
+pub fn spectre_open_visual(select_word: bool) -> KeymapFunction {
+    Rc::new(move |_| {
+        let func = spectre_func("spectre_open_visual_func")?;
         _ = func.call::<_, Value>(lua_value!({
             "select_word" => select_word,
         }));
@@ -25,15 +33,15 @@ pub fn spectre_open_visual(select_word: bool) -> Rc<dyn Fn() -> Result<()>> {
     })
 }
 
-pub fn spectre_open_file_search() -> Rc<dyn Fn() -> Result<()>> {
-    Rc::new(move || {
-        let func: Function = mlua::lua().named_registry_value("spectre_open_file_search_func")?;
+pub fn spectre_open_file_search() -> KeymapFunction {
+    Rc::new(move |_| {
+        let func = spectre_func("spectre_open_file_search_func")?;
         _ = func.call::<_, Value>(());
         Ok(())
     })
 }
 
-pub fn setup_spectre() -> Result<()> {
+fn setup_spectre() -> Result<()> {
     let lua = mlua::lua();
 
     let spectre: Table = require_plugin("spectre")?;
@@ -46,5 +54,19 @@ pub fn setup_spectre() -> Result<()> {
     lua.set_named_registry_value("spectre_open_visual_func", open_visual_func)?;
     lua.set_named_registry_value("spectre_open_file_search_func", open_file_search_func)?;
 
+    reload::track_registry_value("spectre_toggle_func");
+    reload::track_registry_value("spectre_open_visual_func");
+    reload::track_registry_value("spectre_open_file_search_func");
+
     Ok(())
 }
+
+/// Loaded by the first spectre keymap action
+pub fn spectre_plugin() -> Box<dyn Plugin> {
+    Box::new(
+        LuaPlugin::<(), ()>::builder("spectre")
+            .lazy()
+            .post_setup(|()| setup_spectre())
+            .build(),
+    )
+}
@@ -1,46 +1,18 @@
+use super::{lua_plugin::LuaPlugin, plugin::Plugin};
 use crate::{
     Result, Error,
+    log_error,
     mlua::{self, Table, Function, Value},
-    nvim::{
+    nvim::api::{
         self,
-        api::{
-            self,
-            types::{CommandNArgs, CommandArgs},
-            opts::CreateCommandOpts,
-        },
+        types::{CommandNArgs, CommandArgs},
+        opts::CreateCommandOpts,
     },
     nvim_helper::{lua_value, lua_plugins::require_plugin},
+    panic_log, reload,
 };
 
-pub fn setup_telescope() -> Result<()> {
-    let telescope: Table = require_plugin("telescope")?;
-
-    let setup_func: Function = telescope.get("setup")?;
-    _ = setup_func.call::<_, Value>(lua_value!({
-        "defaults" => {
-            "vimgrep_arguments" => [
-                "rg",
-                "--color=never",
-                "--no-heading",
-                "--with-filename",
-                "--line-number",
-                "--column",
-                "--smart-case",
-                "--hidden",
-                "--glob", "!**/.git/*"
-            ],
-            "path_display" => [ "truncate" ],
-        },
-        "extensions" => {
-            "fzf" => {
-                "fuzzy" => true,
-                "override_generic_sorter" => true,
-                "override_file_sorter" => true,
-                "case_mode" => "smart_case",
-            },
-        },
-    }));
-
+fn setup_telescope_call(telescope: &Table) -> Result<()> {
     let load_extension: Function = telescope.get("load_extension")?;
     _ = load_extension.call::<_, Value>("fzf")?;
 
@@ -50,22 +22,66 @@ pub fn setup_telescope() -> Result<()> {
     api::create_user_command(
         "TelescopeCall",
         move |args: CommandArgs| -> Result<()> {
-            let builtin: Table = mlua::lua().registry_value(&builtin_key)?;
+            panic_log::guard("command TelescopeCall", || {
+                let builtin: Table = mlua::lua().registry_value(&builtin_key)?;
 
-            let [func_name,] = args.fargs.as_slice() else {
-                return Err(Error::InvalidType);
-            };
+                let [func_name,] = args.fargs.as_slice() else {
+                    return Err(Error::InvalidType);
+                };
 
-            let func: Function = builtin.get(func_name.to_string()).inspect_err(|_| {
-                nvim::print!("Invalid telecope func");
-            })?;
+                let func: Function = builtin.get(func_name.to_string()).inspect_err(|_| {
+                    log_error!("Invalid telescope func: {func_name}");
+                })?;
 
-            _ = func.call::<_, Value>(mlua::lua().create_table()?)?;
+                _ = func.call::<_, Value>(mlua::lua().create_table()?)?;
 
-            Ok(())
+                Ok(())
+            })
+            .unwrap_or(Ok(()))
         },
         &CreateCommandOpts::builder().nargs(CommandNArgs::One).build()
     )?;
+    reload::track_command("TelescopeCall");
 
     Ok(())
 }
+
+/// Loaded on the first `TelescopeCall`
+pub fn telescope_plugin() -> Box<dyn Plugin> {
+    Box::new(
+        LuaPlugin::<_, ()>::builder("telescope")
+            .requires_module("plenary")
+            // telescope-fzf-native, loaded as an extension in `setup_telescope_call`
+            .requires_module("fzf_lib")
+            .lazy_on_command("TelescopeCall")
+            .pre_setup(|| Ok(lua_value!({
+                "defaults" => {
+                    "vimgrep_arguments" => [
+                        "rg",
+                        "--color=never",
+                        "--no-heading",
+                        "--with-filename",
+                        "--line-number",
+                        "--column",
+                        "--smart-case",
+                        "--hidden",
+                        "--glob", "!**/.git/*"
+                    ],
+                    "path_display" => [ "truncate" ],
+                },
+                "extensions" => {
+                    "fzf" => {
+                        "fuzzy" => true,
+                        "override_generic_sorter" => true,
+                        "override_file_sorter" => true,
+                        "case_mode" => "smart_case",
+                    },
+                },
+            })))
+            .post_setup(|()| {
+                let telescope: Table = require_plugin("telescope")?;
+                setup_telescope_call(&telescope)
+            })
+            .build(),
+    )
+}
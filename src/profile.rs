@@ -0,0 +1,276 @@
+use crate::{
+    Result, log_error, log_info, log_warn, nvim_dir, panic_log,
+    nvim::api::{
+        self,
+        opts::CreateCommandOpts,
+        types::{CommandArgs, CommandNArgs},
+    },
+    scratch::show_scratch,
+};
+
+use serde::Serialize;
+use std::{
+    cell::{Cell, RefCell},
+    fs,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Step wrapping the whole setup, its duration is shown as the total
+pub const TOTAL_STEP: &str = "setup_config";
+
+#[derive(Clone, Debug)]
+pub struct ProfileEntry {
+    pub step: String,
+    pub duration: Duration,
+    /// Number of `measure` calls the step ran in
+    pub depth: usize,
+}
+
+#[derive(Serialize)]
+struct ProfileStep {
+    step: String,
+    ms: f64,
+    depth: usize,
+}
+
+#[derive(Serialize)]
+struct ProfileDump {
+    timestamp: u64,
+    total_ms: f64,
+    steps: Vec<ProfileStep>,
+}
+
+thread_local! {
+    /// In the order the steps started, children after their parent
+    static PROFILE: RefCell<Vec<ProfileEntry>> = const { RefCell::new(Vec::new()) };
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Returns the index of the entry
+fn push(step: impl Into<String>, duration: Duration) -> usize {
+    PROFILE.with_borrow_mut(|profile| {
+        profile.push(ProfileEntry {
+            step: step.into(),
+            duration,
+            depth: DEPTH.get(),
+        });
+        profile.len() - 1
+    })
+}
+
+pub fn record(step: impl Into<String>, duration: Duration) {
+    push(step, duration);
+}
+
+pub fn clear() {
+    PROFILE.with_borrow_mut(|profile| profile.clear());
+    DEPTH.set(0);
+}
+
+/// Steps measured or recorded in `f` are nested under `step`
+pub fn measure<T>(step: &str, f: impl FnOnce() -> T) -> T {
+    let _context = panic_log::enter(step);
+    let index = push(step, Duration::ZERO);
+    let start = Instant::now();
+
+    DEPTH.set(DEPTH.get() + 1);
+    let result = f();
+    DEPTH.set(DEPTH.get().saturating_sub(1));
+
+    PROFILE.with_borrow_mut(|profile| {
+        if let Some(entry) = profile.get_mut(index) {
+            entry.duration = start.elapsed();
+        }
+    });
+    result
+}
+
+fn as_ms(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Sorts the steps of `entries` slowest first, each followed by its own children sorted the same
+/// way. The first entry has to be one of the outermost steps.
+fn sort_tree(entries: &[ProfileEntry]) -> Vec<ProfileEntry> {
+    let Some(depth) = entries.first().map(|e| e.depth) else {
+        return Vec::new();
+    };
+
+    let mut subtrees = Vec::new();
+    let mut start = 0;
+    for (i, entry) in entries.iter().enumerate().skip(1) {
+        if entry.depth <= depth {
+            subtrees.push(&entries[start..i]);
+            start = i;
+        }
+    }
+    subtrees.push(&entries[start..]);
+    subtrees.sort_by(|a, b| b[0].duration.cmp(&a[0].duration));
+
+    subtrees
+        .into_iter()
+        .flat_map(|subtree| {
+            let mut sorted = vec![subtree[0].clone()];
+            sorted.extend(sort_tree(&subtree[1..]));
+            sorted
+        })
+        .collect()
+}
+
+/// Total duration and the remaining steps as a tree, depth starting at 0
+fn sorted_entries() -> (Duration, Vec<ProfileEntry>) {
+    let mut entries = PROFILE.with_borrow(|profile| profile.clone());
+
+    let top_level = entries.iter().filter(|e| e.depth == 0);
+    let total = match entries.iter().find(|e| e.step == TOTAL_STEP) {
+        Some(entry) => entry.duration,
+        None => top_level.map(|e| e.duration).sum(),
+    };
+
+    entries.retain(|e| e.step != TOTAL_STEP);
+    let min_depth = entries.iter().map(|e| e.depth).min().unwrap_or_default();
+    for entry in &mut entries {
+        entry.depth -= min_depth;
+    }
+
+    (total, sort_tree(&entries))
+}
+
+fn render_profile() -> Vec<String> {
+    let (total, entries) = sorted_entries();
+
+    let mut lines = vec![
+        format!("{:<50} {:>10} {:>7}", "step", "ms", "%"),
+        "-".repeat(69),
+    ];
+    for entry in entries {
+        let percent = match total.is_zero() {
+            true => 0.0,
+            false => entry.duration.as_secs_f64() / total.as_secs_f64() * 100.0,
+        };
+        lines.push(format!(
+            "{:<50} {:>10.2} {:>6.1}%",
+            format!("{}{}", "  ".repeat(entry.depth), entry.step),
+            as_ms(entry.duration),
+            percent,
+        ));
+    }
+    lines.push("-".repeat(69));
+    lines.push(format!("{:<50} {:>10.2}", "total", as_ms(total)));
+
+    lines
+}
+
+fn dump_profile() -> Result<()> {
+    let (total, entries) = sorted_entries();
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+
+    let dump = ProfileDump {
+        timestamp,
+        total_ms: as_ms(total),
+        steps: entries
+            .into_iter()
+            .map(|e| ProfileStep {
+                step: e.step,
+                ms: as_ms(e.duration),
+                depth: e.depth,
+            })
+            .collect(),
+    };
+
+    let path = nvim_dir().join(format!("profile-{timestamp}.json"));
+    let json = match serde_json::to_string_pretty(&dump) {
+        Ok(json) => json,
+        Err(e) => {
+            log_error!("Failed to serialize profile: {e}");
+            return Ok(());
+        }
+    };
+    match fs::write(&path, json) {
+        Ok(()) => log_info!("Profile written to {}", path.display()),
+        Err(e) => log_error!("Failed to write {}: {e}", path.display()),
+    }
+
+    Ok(())
+}
+
+pub fn setup_profile() -> Result<()> {
+    api::create_user_command(
+        "ConfigProfile",
+        |args: CommandArgs| -> Result<()> {
+            panic_log::guard("command ConfigProfile", || match args.fargs.as_slice() {
+                [] => show_scratch("nvim-config://profile", render_profile()),
+                [arg] if arg == "json" => dump_profile(),
+                _ => {
+                    log_warn!("Usage: ConfigProfile [json]");
+                    Ok(())
+                }
+            })
+            .unwrap_or(Ok(()))
+        },
+        &CreateCommandOpts::builder().nargs(CommandNArgs::ZeroOrOne).build(),
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(step: &str, ms: u64, depth: usize) -> ProfileEntry {
+        ProfileEntry {
+            step: step.to_string(),
+            duration: Duration::from_millis(ms),
+            depth,
+        }
+    }
+
+    fn steps(entries: &[ProfileEntry]) -> Vec<(&str, usize)> {
+        entries.iter().map(|e| (e.step.as_str(), e.depth)).collect()
+    }
+
+    #[test]
+    fn sorts_slowest_first() {
+        let entries = [entry("a", 1, 0), entry("b", 3, 0), entry("c", 2, 0)];
+        assert_eq!(steps(&sort_tree(&entries)), [("b", 0), ("c", 0), ("a", 0)]);
+    }
+
+    #[test]
+    fn children_stay_under_their_parent() {
+        let entries = [
+            entry("plugins", 10, 0),
+            entry("leap", 1, 1),
+            entry("lsp", 6, 1),
+            entry("rust_analyzer", 2, 2),
+            entry("clangd", 3, 2),
+            entry("keymaps", 20, 0),
+            entry("clear_keymap n", 15, 1),
+        ];
+
+        let expected = [
+            ("keymaps", 0),
+            ("clear_keymap n", 1),
+            ("plugins", 0),
+            ("lsp", 1),
+            ("clangd", 2),
+            ("rust_analyzer", 2),
+            ("leap", 1),
+        ];
+        assert_eq!(steps(&sort_tree(&entries)), expected);
+    }
+
+    #[test]
+    fn equal_durations_keep_their_order() {
+        let entries = [entry("a", 1, 0), entry("b", 1, 0), entry("c", 1, 0)];
+        assert_eq!(steps(&sort_tree(&entries)), [("a", 0), ("b", 0), ("c", 0)]);
+    }
+
+    #[test]
+    fn empty() {
+        assert!(sort_tree(&[]).is_empty());
+    }
+}
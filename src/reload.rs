@@ -0,0 +1,144 @@
+//! `:ConfigReload` tears down what the config registered and runs the setup again.
+//!
+//! Only state created through the config is undone: keymaps from `setup_keymap` /
+//! `setup_buf_keymap` and the blanked keys, user commands like `TelescopeCall`, named
+//! registry values, options (set back to the value they had before the config) and the
+//! triggers of lazy plugins that haven't loaded yet.
+//! The rest persists for the lifetime of the process:
+//! - the Rust code itself, the loaded library can't be replaced, rebuilding requires a restart
+//! - Lua modules stay in `package.loaded`, plugin `setup` functions are called again on the
+//!   same module instances (lspconfig servers are set up again, already attached clients keep
+//!   running)
+//! - buffer keymaps from LSP `on_attach` are removed and come back on the next attach
+//! - the panic hook and thread-local state other than the startup report, profile, keymap lint and keymap registry
+
+use crate::{
+    Result, log_error, log_info,
+    mlua::{self, Value},
+    nvim::{
+        Object,
+        api::{
+            self, Buffer,
+            opts::{CreateCommandOpts, OptionOpts},
+            types::{CommandArgs, Mode},
+        },
+    },
+    keymap_lint, keymap_remapping, keymap_snapshot, panic_log, plugins, profile, report,
+};
+
+use std::{cell::RefCell, collections::HashMap};
+
+#[derive(Default)]
+struct Registered {
+    /// Lhs and buffer handle for buffer-local maps -> modes, a map set again is tracked once
+    keymaps: HashMap<(String, Option<i32>), Vec<Mode>>,
+    commands: Vec<String>,
+    registry_values: Vec<String>,
+    /// Option name and its value before the config first set it
+    options: Vec<(String, Object)>,
+}
+
+thread_local! {
+    static REGISTERED: RefCell<Registered> = RefCell::new(Registered::default());
+}
+
+pub fn track_keymap(mode: Mode, lhs: &str, buffer: Option<i32>) {
+    REGISTERED.with_borrow_mut(|r| {
+        let modes = r.keymaps.entry((lhs.to_string(), buffer)).or_default();
+        if !modes.contains(&mode) {
+            modes.push(mode);
+        }
+    });
+}
+
+pub fn track_command(name: &str) {
+    REGISTERED.with_borrow_mut(|r| r.commands.push(name.to_string()));
+}
+
+pub fn track_registry_value(name: &str) {
+    REGISTERED.with_borrow_mut(|r| r.registry_values.push(name.to_string()));
+}
+
+/// Call before setting the global option `name`, the value it has now is restored on teardown
+pub fn track_option(name: &str) {
+    if REGISTERED.with_borrow(|r| r.options.iter().any(|(n, _)| n == name)) {
+        return;
+    }
+
+    match api::get_option_value::<Object>(name, &OptionOpts::builder().build()) {
+        Ok(value) => REGISTERED.with_borrow_mut(|r| r.options.push((name.to_string(), value))),
+        Err(e) => log_error!("Failed to read option {name} before setting it: {e}"),
+    }
+}
+
+/// Deletes the keymaps the config set, also used by `:KeymapRestore`
+pub fn teardown_keymaps() {
+    let keymaps = REGISTERED.with_borrow_mut(|r| std::mem::take(&mut r.keymaps));
+
+    // Errors are expected here, maps may already be gone (wiped buffers, lazy stubs etc.)
+    for ((lhs, buffer), modes) in keymaps {
+        for mode in modes {
+            match buffer {
+                Some(handle) => {
+                    let mut buf = Buffer::from(handle);
+                    if buf.is_valid() {
+                        _ = buf.del_keymap(mode, &lhs);
+                    }
+                }
+                None => {
+                    _ = api::del_keymap(mode, &lhs);
+                }
+            }
+        }
+    }
+}
+
+fn teardown() {
+    teardown_keymaps();
+    let registered = REGISTERED.take();
+
+    for command in registered.commands {
+        _ = api::del_user_command(&command);
+    }
+
+    let lua = mlua::lua();
+    for name in registered.registry_values {
+        _ = lua.set_named_registry_value(&name, Value::Nil);
+    }
+
+    for (name, value) in registered.options {
+        if let Err(e) = api::set_option_value(&name, value, &OptionOpts::builder().build()) {
+            log_error!("Failed to restore option {name}: {e}");
+        }
+    }
+
+    plugins::lazy::clear();
+}
+
+pub fn reload() {
+    log_info!("Reloading nvim-config");
+
+    teardown();
+    keymap_lint::clear();
+    keymap_remapping::clear_registry();
+    keymap_snapshot::start_run();
+    report::clear();
+    profile::clear();
+
+    crate::setup_config(());
+}
+
+pub fn setup_reload_command() -> Result<()> {
+    api::create_user_command(
+        "ConfigReload",
+        |_: CommandArgs| -> Result<()> {
+            if panic_log::guard("command ConfigReload", reload).is_none() {
+                log_error!("Reload panicked, the config may be partially set up");
+            }
+            Ok(())
+        },
+        &CreateCommandOpts::builder().build(),
+    )?;
+
+    Ok(())
+}
@@ -0,0 +1,53 @@
+//! Dot-repeat for function actions: the keypress sets `operatorfunc` to the action and feeds
+//! `g@l`, so `.` runs `g@l` again and calls the action once more. The count goes with it,
+//! `3<key>` feeds `3g@l` and `5.` replaces the count like for any other change.
+
+use crate::{
+    Result, log_error, panic_log,
+    keymap_remapping::{feedkeys, KeymapContext, KeymapFunction},
+    mlua,
+    nvim::api::{self, opts::OptionOpts},
+};
+
+use std::rc::Rc;
+
+/// Global Lua function `operatorfunc` points to, replaced by each repeatable keypress
+const OPERATORFUNC: &str = "__nvim_config_operatorfunc";
+
+fn set_operatorfunc(func: KeymapFunction) -> Result<()> {
+    let lua = mlua::lua();
+
+    let operatorfunc = lua.create_function(move |_, _motion_type: String| {
+        let run = || func(&KeymapContext::current()?);
+        if let Some(Err(e)) = panic_log::guard("repeatable keymap", run) {
+            log_error!(title = "nvim-config: keymap", "Repeatable action failed: {e}");
+        }
+        Ok(())
+    })?;
+    lua.globals().set(OPERATORFUNC, operatorfunc)?;
+
+    api::set_option_value(
+        "operatorfunc",
+        format!("v:lua.{OPERATORFUNC}"),
+        &OptionOpts::builder().build(),
+    )?;
+
+    Ok(())
+}
+
+/// Makes `func` repeatable with `.` in normal mode, other modes call it directly
+pub fn repeatable(func: KeymapFunction) -> KeymapFunction {
+    Rc::new(move |context| {
+        // `l` fails on an empty line and the operator would be cancelled
+        if context.mode != "n" || api::get_current_line()?.is_empty() {
+            return func(context);
+        }
+
+        set_operatorfunc(func.clone())?;
+        let count = match context.count {
+            0 => String::new(),
+            count => count.to_string(),
+        };
+        feedkeys(&format!("{count}g@l"), "n")
+    })
+}
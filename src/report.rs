@@ -0,0 +1,268 @@
+use crate::{
+    Result, log_error, log_warn, nvim_dir, panic_log,
+    mlua::{self, Function, Table, Value},
+    nvim::api::{
+        self,
+        opts::{CreateCommandOpts, OptionOpts},
+        types::CommandArgs,
+    },
+    profile,
+    scratch::show_scratch,
+};
+
+use std::{
+    cell::RefCell,
+    error::Error,
+    fmt, fs,
+    result::Result as StdResult,
+    time::{Duration, Instant},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Subsystem {
+    Settings,
+    Plugin,
+    Keymap,
+    Lsp,
+}
+
+impl fmt::Display for Subsystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Subsystem::Settings => "settings",
+            Subsystem::Plugin => "plugin",
+            Subsystem::Keymap => "keymap",
+            Subsystem::Lsp => "lsp",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    Skipped,
+    Failed,
+}
+
+#[derive(Clone, Debug)]
+pub struct ReportEntry {
+    pub subsystem: Subsystem,
+    pub name: String,
+    pub status: Status,
+    pub duration: Duration,
+    /// Error followed by its sources
+    pub errors: Vec<String>,
+}
+
+impl ReportEntry {
+    pub fn new(subsystem: Subsystem, name: impl Into<String>) -> Self {
+        ReportEntry {
+            subsystem,
+            name: name.into(),
+            status: Status::Ok,
+            duration: Duration::ZERO,
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn duration(mut self, duration: Duration) -> Self {
+        self.duration = duration;
+        self
+    }
+
+    pub fn failed(mut self, error: &dyn Error) -> Self {
+        self.status = Status::Failed;
+        self.errors = error_chain(error);
+        self
+    }
+
+    pub fn skipped(mut self, error: &dyn Error) -> Self {
+        self.status = Status::Skipped;
+        self.errors = error_chain(error);
+        self
+    }
+}
+
+thread_local! {
+    static REPORT: RefCell<Vec<ReportEntry>> = const { RefCell::new(Vec::new()) };
+}
+
+fn error_chain(error: &dyn Error) -> Vec<String> {
+    let mut chain = vec![error.to_string()];
+    let mut source = error.source();
+    while let Some(e) = source {
+        chain.push(e.to_string());
+        source = e.source();
+    }
+    chain
+}
+
+/// Timed entries also end up in the profile
+pub fn record(entry: ReportEntry) {
+    if !entry.duration.is_zero() {
+        profile::record(format!("{}: {}", entry.subsystem, entry.name), entry.duration);
+    }
+    REPORT.with_borrow_mut(|report| report.push(entry));
+}
+
+/// Runs `f` and records its duration and result
+pub fn timed<T, E: Error>(
+    subsystem: Subsystem,
+    name: &str,
+    f: impl FnOnce() -> StdResult<T, E>,
+) -> StdResult<T, E> {
+    let _context = panic_log::enter(format!("{subsystem} {name}"));
+    let start = Instant::now();
+    let result = f();
+    let entry = ReportEntry::new(subsystem, name).duration(start.elapsed());
+
+    record(match &result {
+        Ok(_) => entry,
+        Err(e) => entry.failed(e),
+    });
+
+    result
+}
+
+pub fn clear() {
+    REPORT.with_borrow_mut(|report| report.clear());
+}
+
+pub fn entries() -> Vec<ReportEntry> {
+    REPORT.with_borrow(|report| report.clone())
+}
+
+pub fn print_summary() {
+    let failed = entries()
+        .iter()
+        .filter(|e| e.status != Status::Ok)
+        .count();
+
+    if failed > 0 {
+        log_warn!("{failed} startup problem(s), see :ConfigHealth");
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    format!("{:.2}ms", duration.as_secs_f64() * 1000.0)
+}
+
+fn render_report() -> Vec<String> {
+    let entries = entries();
+    let mut lines = vec!["nvim-config startup report".to_string(), String::new()];
+
+    for subsystem in [Subsystem::Settings, Subsystem::Plugin, Subsystem::Keymap, Subsystem::Lsp] {
+        let section: Vec<_> = entries.iter().filter(|e| e.subsystem == subsystem).collect();
+        if section.is_empty() {
+            continue;
+        }
+
+        lines.push(format!("## {subsystem}"));
+        for entry in section {
+            let status = match entry.status {
+                Status::Ok => "OK",
+                Status::Skipped => "SKIPPED",
+                Status::Failed => "FAILED",
+            };
+            lines.push(format!(
+                "  {status:<8} {:<30} {:>10}",
+                entry.name,
+                format_duration(entry.duration),
+            ));
+            for error in &entry.errors {
+                lines.push(format!("           {error}"));
+            }
+        }
+        lines.push(String::new());
+    }
+
+    lines
+}
+
+fn health_check(lua: &mlua::Lua, _: ()) -> mlua::Result<()> {
+    let health: Table = lua.globals().get::<_, Table>("vim")?.get("health")?;
+
+    let start: Function = health.get("start")?;
+    let ok: Function = health.get("ok")?;
+    let warn: Function = health.get("warn")?;
+    let error: Function = health.get("error")?;
+
+    let entries = entries();
+    for subsystem in [Subsystem::Settings, Subsystem::Plugin, Subsystem::Keymap, Subsystem::Lsp] {
+        start.call::<_, Value>(format!("nvim-config: {subsystem}"))?;
+
+        for entry in entries.iter().filter(|e| e.subsystem == subsystem) {
+            let message = match entry.errors.is_empty() {
+                true => format!("{} ({})", entry.name, format_duration(entry.duration)),
+                false => format!("{}: {}", entry.name, entry.errors.join(": ")),
+            };
+            match entry.status {
+                Status::Ok => ok.call::<_, Value>(message)?,
+                Status::Skipped => warn.call::<_, Value>(message)?,
+                Status::Failed => error.call::<_, Value>(message)?,
+            };
+        }
+    }
+
+    Ok(())
+}
+
+/// `:checkhealth` only discovers providers through runtime files, so a stub module
+/// is written to the nvim dir and its entry in `package.loaded` is replaced by ours.
+fn setup_health_provider() -> Result<()> {
+    let runtime_dir = nvim_dir().join("runtime");
+    let module_dir = runtime_dir.join("lua").join("nvim_config");
+    let module_path = module_dir.join("health.lua");
+
+    if !module_path.exists() {
+        if let Err(e) = fs::create_dir_all(&module_dir).and_then(|_| {
+            fs::write(
+                &module_path,
+                "return { check = function() vim.health.error('nvim-config is not loaded') end }\n",
+            )
+        }) {
+            log_error!("Failed to write health module: {e}");
+        }
+    }
+
+    let runtime_dir = runtime_dir.to_string_lossy();
+    let current_rtp: String = api::get_option_value("runtimepath", &OptionOpts::builder().build())?;
+    if !current_rtp.split(',').any(|p| p == runtime_dir) {
+        api::set_option_value(
+            "runtimepath",
+            format!("{runtime_dir},{current_rtp}"),
+            &OptionOpts::builder().build(),
+        )?;
+    }
+
+    let lua = mlua::lua();
+    let module = lua.create_table()?;
+    module.set(
+        "check",
+        lua.create_function(|lua, args: ()| {
+            panic_log::guard("checkhealth", || health_check(lua, args)).unwrap_or(Ok(()))
+        })?,
+    )?;
+
+    let loaded: Table = lua.globals().get::<_, Table>("package")?.get("loaded")?;
+    loaded.set("nvim_config.health", module)?;
+
+    Ok(())
+}
+
+pub fn setup_report() -> Result<()> {
+    api::create_user_command(
+        "ConfigHealth",
+        |_: CommandArgs| -> Result<()> {
+            panic_log::guard("command ConfigHealth", || {
+                show_scratch("nvim-config://health", render_report())
+            })
+            .unwrap_or(Ok(()))
+        },
+        &CreateCommandOpts::builder().build(),
+    )?;
+
+    setup_health_provider()?;
+
+    Ok(())
+}
@@ -0,0 +1,18 @@
+use crate::{
+    Result,
+    nvim::api,
+};
+
+/// Opens `lines` in a new read-only scratch buffer in a split
+pub fn show_scratch<L: Into<String>>(name: &str, lines: impl IntoIterator<Item = L>) -> Result<()> {
+    let mut buf = api::create_buf(false, true)?;
+    buf.set_lines(.., false, lines.into_iter().map(Into::into).collect::<Vec<String>>())?;
+    // Fails if a buffer with this name is already open, not worth reporting
+    _ = buf.set_name(name);
+
+    api::command("split")?;
+    api::set_current_buf(&buf)?;
+    api::command("setlocal nomodifiable bufhidden=wipe")?;
+
+    Ok(())
+}
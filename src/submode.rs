@@ -0,0 +1,136 @@
+//! Hydra-style submodes: the key entering one (`<Space>w`) keeps its single-key actions
+//! active, so `<Space>w` `jj+++` focuses and resizes without retyping the prefix. A popup
+//! lists the keys while the submode is active. `<Esc>`, a key the submode doesn't map or
+//! `TIMEOUT_MS` without a key leave it, an unmapped key then acts as if typed on its own.
+//!
+//! Digits before a key are its count, the count the submode was entered with applies to the
+//! first key.
+
+use crate::{
+    Result,
+    key::KeySequence,
+    keymap_remapping::{feedkeys, KeymapContext, NvimKeymap},
+    mlua::{self, Function},
+    nvim_helper::lua::lua_get_global_path,
+    which_key::{read_key, render, Popup},
+};
+
+/// Time the submode waits for the next key
+const TIMEOUT_MS: i64 = 3000;
+
+#[derive(Clone, Debug)]
+pub struct Submode {
+    pub name: String,
+    /// Mostly single keys, the lhs is looked up as one typed key
+    pub keymap: NvimKeymap,
+}
+
+impl Submode {
+    pub fn new(name: impl Into<String>, keymap: NvimKeymap) -> Self {
+        Submode { name: name.into(), keymap }
+    }
+
+    /// Same submode with its keys rewritten by `f`, for positional keys and the layout
+    pub fn map_keys(&self, f: impl Fn(&KeySequence) -> KeySequence) -> Submode {
+        let keymap = self.keymap.iter().map(|(keys, entry)| (f(keys), entry.clone())).collect();
+        Submode::new(self.name.clone(), keymap)
+    }
+
+    /// Runs typed actions until the submode is left
+    pub fn run(&self, context: &KeymapContext) -> Result<()> {
+        let entries: Vec<_> = self.keymap.iter().map(|(keys, entry)| (keys.clone(), entry.clone())).collect();
+        let lines = render(&KeySequence::default(), &entries);
+        let mut context = context.clone();
+
+        loop {
+            let title = match context.count {
+                0 => format!(" {} mode ", self.name),
+                count => format!(" {} mode ({count}) ", self.name),
+            };
+            let popup = Popup::open(&title, lines.clone())?;
+            let key = read_key_timeout();
+            popup.close()?;
+
+            let Some(key) = key? else {
+                return Ok(());
+            };
+
+            if let Some(count) = extend_count(context.count, &key) {
+                context.count = count;
+                continue;
+            }
+
+            match self.keymap.get(&key) {
+                Some(entry) => entry.mapped_action().run_immediately(&context)?,
+                None => return feedkeys(&key.to_string(), "m"),
+            }
+            context.count = 0;
+        }
+    }
+}
+
+/// `count` with `key` typed after it, `None` if `key` isn't a digit of the count.
+/// `0` without a count is a key like any other.
+fn extend_count(count: u32, key: &KeySequence) -> Option<u32> {
+    let [key] = key.keys() else {
+        return None;
+    };
+    let digit = key.as_char()?.to_digit(10)?;
+    (digit != 0 || count != 0).then(|| count.saturating_mul(10).saturating_add(digit))
+}
+
+/// Next typed key, `None` after `TIMEOUT_MS` or when cancelled
+fn read_key_timeout() -> Result<Option<KeySequence>> {
+    let wait: Function = lua_get_global_path("vim.wait")?;
+    let typeahead: Function = mlua::lua()
+        .load("return function() return vim.fn.getchar(1) ~= 0 end")
+        .eval()?;
+
+    let typed: bool = wait.call((TIMEOUT_MS, typeahead, 20))?;
+    match typed {
+        true => read_key(),
+        false => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Count after typing `keys` one by one, and the keys that weren't part of it
+    fn typed(count: u32, keys: &[&str]) -> (u32, Vec<String>) {
+        let mut rest = Vec::new();
+        let count = keys.iter().fold(count, |count, lhs| {
+            let key = KeySequence::from(*lhs);
+            extend_count(count, &key).unwrap_or_else(|| {
+                rest.push(lhs.to_string());
+                count
+            })
+        });
+        (count, rest)
+    }
+
+    #[test]
+    fn digits_build_a_count() {
+        assert_eq!(typed(0, &["1", "2"]), (12, vec![]));
+        assert_eq!(typed(3, &["4"]), (34, vec![]));
+    }
+
+    #[test]
+    fn zero_needs_a_count() {
+        assert_eq!(typed(0, &["0"]), (0, vec!["0".to_string()]));
+        assert_eq!(typed(0, &["1", "0"]), (10, vec![]));
+        assert_eq!(typed(2, &["0"]), (20, vec![]));
+    }
+
+    #[test]
+    fn other_keys_are_not_digits() {
+        let keys = ["j", "<C-1>", "<k1>", "12"];
+        assert_eq!(typed(0, &keys), (0, keys.map(String::from).to_vec()));
+    }
+
+    #[test]
+    fn count_saturates() {
+        assert_eq!(typed(u32::MAX / 2, &["9", "9"]), (u32::MAX, vec![]));
+    }
+}
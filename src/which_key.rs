@@ -0,0 +1,200 @@
+//! which-key style popup: typing a prefix (`<Space>`, `.`, `z`) opens a floating window listing
+//! the continuations registered through `setup_keymap` / `setup_buf_keymap`, grouped by category,
+//! then runs the one that is typed.
+
+use crate::{
+    Result, log_error, panic_log, reload,
+    key::KeySequence,
+    keymap_remapping::{feedkeys, with_registry, KeymapContext, KeymapEntry, NvimKeymap},
+    mlua::{self, Function},
+    nvim::api::{
+        self, Buffer,
+        opts::{OptionOpts, SetKeymapOpts},
+        types::Mode,
+    },
+    nvim_helper::{lua::lua_get_global_path, lua_value},
+};
+
+use std::collections::BTreeMap;
+
+/// Prefixes that open the popup in normal and visual mode
+const PREFIXES: [&str; 3] = [" ", ".", "z"];
+pub const DEFAULT_GROUP: &str = "Other";
+/// `desc` of the prefix maps
+pub const PREFIX_DESC: &str = "which-key";
+/// Longer lists are cut off
+const MAX_HEIGHT: usize = 20;
+
+/// Entries starting with `typed` in the current buffer, buffer-local maps hide global ones
+fn continuations(mode: Mode, typed: &KeySequence) -> Vec<(KeySequence, KeymapEntry)> {
+    let buffer = api::get_current_buf().handle();
+
+    with_registry(|registry| {
+        let mut continuations = NvimKeymap::new();
+        for (registered_buffer, keymap) in registry.by_mode(mode) {
+            let matching = keymap.iter()
+                .filter(|(keys, _)| keys.starts_with(typed))
+                .map(|(keys, entry)| (keys.clone(), entry.clone()))
+                .collect();
+            match registered_buffer {
+                Some(handle) if handle == buffer => continuations.override_with(matching),
+                Some(_) => {}
+                None => continuations.merge(matching),
+            }
+        }
+
+        continuations.into_iter().collect()
+    })
+}
+
+pub fn render(typed: &KeySequence, candidates: &[(KeySequence, KeymapEntry)]) -> Vec<String> {
+    let mut groups: BTreeMap<&str, Vec<(String, String)>> = BTreeMap::new();
+    for (keys, entry) in candidates {
+        groups
+            .entry(entry.group.as_deref().unwrap_or(DEFAULT_GROUP))
+            .or_default()
+            .push((keys.skip(typed.len()).to_string(), entry.description()));
+    }
+
+    let mut lines = Vec::new();
+    for (group, mut entries) in groups {
+        entries.sort();
+        if !lines.is_empty() {
+            lines.push(String::new());
+        }
+        lines.push(group.to_string());
+        lines.extend(entries.iter().map(|(keys, desc)| format!("  {keys:<8} {desc}")));
+    }
+
+    lines
+}
+
+pub struct Popup {
+    window: i64,
+    buffer: i32,
+}
+
+impl Popup {
+    pub fn open(title: &str, lines: Vec<String>) -> Result<Popup> {
+        let height = lines.len().min(MAX_HEIGHT);
+        let mut buf = api::create_buf(false, true)?;
+        buf.set_lines(.., false, lines)?;
+
+        let columns: i64 = api::get_option_value("columns", &OptionOpts::builder().build())?;
+        let editor_lines: i64 = api::get_option_value("lines", &OptionOpts::builder().build())?;
+        // Above the statusline and the command line, the border takes two more
+        let row = (editor_lines - height as i64 - 4).max(0);
+        let width = (columns - 2).max(1);
+
+        let open_win: Function = lua_get_global_path("vim.api.nvim_open_win")?;
+        let window: i64 = open_win.call((buf.handle(), false, lua_value!({
+            "relative" => "editor",
+            "row" => row,
+            "col" => 0,
+            "width" => width,
+            "height" => height,
+            "style" => "minimal",
+            "border" => "rounded",
+            "title" => title,
+            "noautocmd" => true,
+        })))?;
+        api::command("redraw")?;
+
+        Ok(Popup { window, buffer: buf.handle() })
+    }
+
+    pub fn close(self) -> Result<()> {
+        let close_win: Function = lua_get_global_path("vim.api.nvim_win_close")?;
+        let delete_buf: Function = lua_get_global_path("vim.api.nvim_buf_delete")?;
+        close_win.call::<_, ()>((self.window, true))?;
+        delete_buf.call::<_, ()>((self.buffer, lua_value!({ "force" => true })))?;
+        Ok(())
+    }
+}
+
+/// Next typed key, `None` when cancelled with <Esc> or <C-c>
+pub fn read_key() -> Result<Option<KeySequence>> {
+    let getcharstr: Function = lua_get_global_path("vim.fn.getcharstr")?;
+    let keytrans: Function = lua_get_global_path("vim.fn.keytrans")?;
+
+    // <C-c> interrupts getcharstr
+    let Ok(raw) = getcharstr.call::<_, mlua::String>(()) else {
+        return Ok(None);
+    };
+    let key = KeySequence::from(keytrans.call::<_, String>(raw)?);
+
+    Ok((key != KeySequence::from("<Esc>")).then_some(key))
+}
+
+/// The count typed before the prefix was taken by the prefix keymap, it's passed on here
+fn run(entry: &KeymapEntry) -> Result<()> {
+    entry.mapped_action().run(&KeymapContext::current()?)
+}
+
+fn show(mode: Mode, prefix: &KeySequence) -> Result<()> {
+    let mut typed = prefix.clone();
+
+    loop {
+        let candidates = continuations(mode, &typed);
+        if let Some((_, entry)) = candidates.iter().find(|(keys, _)| *keys == typed) {
+            return run(entry);
+        }
+        if candidates.is_empty() {
+            // Not mapped, the keys after the prefix act as if typed on their own
+            return feedkeys(&typed.skip(prefix.len()).to_string(), "m");
+        }
+
+        let popup = Popup::open(&format!(" {typed} "), render(&typed, &candidates))?;
+        let key = read_key();
+        popup.close()?;
+
+        let Some(key) = key? else {
+            return Ok(());
+        };
+        for key in key.keys() {
+            typed.push(*key);
+        }
+    }
+}
+
+/// Maps the prefixes `keymap` has continuations for to the popup, buffer-locally for `buffer`.
+/// `nowait` so that the popup opens right away instead of after `timeoutlen`.
+pub fn setup_prefixes(mode: Mode, mut buffer: Option<&mut Buffer>, keymap: &NvimKeymap) -> Result<()> {
+    if !matches!(mode, Mode::Normal | Mode::Visual) {
+        return Ok(());
+    }
+
+    for prefix in PREFIXES.map(KeySequence::from) {
+        // A mapping on the prefix itself takes precedence
+        let has_continuations = keymap.keys().any(|k| k.len() > prefix.len() && k.starts_with(&prefix));
+        if keymap.contains_key(&prefix) || !has_continuations {
+            continue;
+        }
+
+        let lhs = prefix.to_string();
+        let context = format!("which-key {lhs}");
+        let opts = SetKeymapOpts::builder()
+            .silent(true)
+            .nowait(true)
+            .desc(PREFIX_DESC)
+            .callback(move |()| {
+                if let Some(Err(e)) = panic_log::guard(&context, || show(mode, &prefix)) {
+                    log_error!(title = "nvim-config: keymap", "which-key popup failed: {e}");
+                }
+            })
+            .build();
+
+        match buffer.as_deref_mut() {
+            Some(buf) => {
+                reload::track_keymap(mode, &lhs, Some(buf.handle()));
+                buf.set_keymap(mode, &lhs, "", &opts)?;
+            }
+            None => {
+                reload::track_keymap(mode, &lhs, None);
+                api::set_keymap(mode, &lhs, "", &opts)?;
+            }
+        }
+    }
+
+    Ok(())
+}